@@ -2,10 +2,11 @@ use super::{FtpError, FtpStream};
 
 use std::fs::File;
 use std::io;
+use std::net::Ipv6Addr;
 use std::path::Path;
 use suppaftp::native_tls::TlsConnector;
 use suppaftp::types::FileType;
-use suppaftp::Mode;
+use suppaftp::{CertificatePin, Mode, VerifyingTlsBackend};
 
 pub fn quit(mut ftp: Option<FtpStream>) {
     if let Some(ftp) = ftp.take() {
@@ -37,7 +38,7 @@ pub fn cdup(ftp: &mut FtpStream) {
     }
 }
 
-pub fn connect(remote: &str, secure: bool) -> Option<FtpStream> {
+pub fn connect(remote: &str, secure: bool, pin: Option<CertificatePin>) -> Option<FtpStream> {
     let mut stream: FtpStream = match FtpStream::connect(remote) {
         Ok(c) => c,
         Err(err) => {
@@ -45,7 +46,7 @@ pub fn connect(remote: &str, secure: bool) -> Option<FtpStream> {
             return None;
         }
     };
-    // if secure, enable TLS
+    // if secure, enable TLS; the certificate is only checked against the pin, if provided
     if secure {
         let ctx = match TlsConnector::builder()
             .danger_accept_invalid_certs(true)
@@ -58,9 +59,19 @@ pub fn connect(remote: &str, secure: bool) -> Option<FtpStream> {
                 return None;
             }
         };
-        // Get address without port
-        let address: &str = remote.split(':').next().unwrap();
-        stream = match stream.into_secure(ctx, address) {
+        // Get address without port; IPv6 addresses with port are enclosed in brackets (e.g. `[::1]:21`)
+        let address: &str = if let Some(addr) = remote.strip_prefix('[') {
+            addr.split(']').next().unwrap()
+        } else if remote.parse::<Ipv6Addr>().is_ok() {
+            remote
+        } else {
+            remote.split(':').next().unwrap()
+        };
+        let secured = match pin {
+            Some(pin) => stream.into_secure(VerifyingTlsBackend::new(ctx, pin), address),
+            None => stream.into_secure(ctx, address),
+        };
+        stream = match secured {
             Ok(s) => s,
             Err(err) => {
                 eprintln!("Failed to setup TLS stream: {}", err);
@@ -1,11 +1,11 @@
 use std::path::PathBuf;
 use std::str::FromStr;
-use suppaftp::Mode;
+use suppaftp::{CertificatePin, Mode};
 
 pub enum Command {
     Appe(PathBuf, String),
     Cdup,
-    Connect(String, bool),
+    Connect(String, bool, Option<CertificatePin>),
     Cwd(String),
     Dele(String),
     Feat,
@@ -52,11 +52,17 @@ impl FromStr for Command {
             },
             "CONNECT" => {
                 let addr: String = args.next().ok_or("Missing `addr` field")?.into();
-                Self::Connect(addr, false)
+                Self::Connect(addr, false, None)
             },
             "CONNECT+S" => {
                 let addr: String = args.next().ok_or("Missing `addr` field")?.into();
-                Self::Connect(addr, true)
+                let pin = match args.next() {
+                    Some(fingerprint) => Some(
+                        CertificatePin::from_sha256_fingerprint(fingerprint).ok_or("Invalid `fingerprint` field")?,
+                    ),
+                    None => None,
+                };
+                Self::Connect(addr, true, pin)
             },
             "CWD" => {
                 let dir: String = args.next().ok_or("Missing `dir` field")?.into();
@@ -30,8 +30,9 @@ fn usage() {
     println!("Available commands:");
     println!("APPE <file> <dest>                  Append content of local file `file` to `dest`");
     println!("CDUP                                Go to parent directory");
-    println!("CONNECT <addr:port>                 Connect to remote host");
-    println!("CONNECT+S <addr:port>               Connect to remote host using FTPS");
+    println!("CONNECT <addr:port>                 Connect to remote host (IPv6 addresses as [addr]:port)");
+    println!("CONNECT+S <addr:port> [fingerprint] Connect to remote host using FTPS (IPv6 addresses as [addr]:port).");
+    println!("                                    If provided, the server certificate must match the SHA-256 fingerprint");
     println!("CWD <dir>                           Change working directory");
     println!("DELE <file>                         Remove file at specified path");
     println!("FEAT                                Get the feature list implemented by the server");
@@ -114,7 +115,7 @@ fn perform(ftp: &mut Option<FtpStream>, command: Command) {
 
 fn perform_uninitialized(command: Command) -> Option<FtpStream> {
     match command {
-        Command::Connect(remote, secure) => connect(remote.as_str(), secure),
+        Command::Connect(remote, secure, pin) => connect(remote.as_str(), secure, pin),
         _ => {
             eprintln!("Can't perform command: you must connect to remote first");
             None
@@ -126,8 +127,8 @@ fn perform_connected(ftp: &mut FtpStream, command: Command) {
     match command {
         Command::Appe(src, dest) => appe(ftp, src.as_path(), dest.as_str()),
         Command::Cdup => cdup(ftp),
-        Command::Connect(remote, secure) => {
-            if let Some(stream) = connect(remote.as_str(), secure) {
+        Command::Connect(remote, secure, pin) => {
+            if let Some(stream) = connect(remote.as_str(), secure, pin) {
                 *ftp = stream;
             }
         }
@@ -4,6 +4,7 @@
 
 use crate::types::FileType;
 
+use std::net::SocketAddr;
 use std::string::ToString;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -11,6 +12,8 @@ use std::string::ToString;
 pub enum Command {
     /// Abort an active file transfer
     Abor,
+    /// Provide the account (e.g. for FTP proxies which require it after login)
+    Acct(String),
     /// Append to file
     Appe(String),
     /// Set auth to TLS
@@ -25,6 +28,12 @@ pub enum Command {
     Cwd(String),
     /// Remove file at specified path
     Dele(String),
+    /// Specifies an address and port to which the server should connect (extended active mode, RFC 2428)
+    Eprt(SocketAddr),
+    /// Extended passive mode (RFC 2428)
+    Epsv,
+    /// Tell the server that only EPSV will be used for data connections (RFC 2428)
+    EpsvAll,
     /// Get the feature list implemented by the server
     Feat,
     /// Language Negotiation
@@ -43,6 +52,8 @@ pub enum Command {
     Mlsd(Option<String>),
     /// Provides data about exactly the object named on its command line in a standardized machine-readable format
     Mlst(Option<String>),
+    /// Ask an FTP proxy to connect to the provided host (non-standard)
+    Open(String),
     /// Select options for a feature (for example OPTS UTF8 ON)
     Opts(String, Option<String>),
     /// Provide login password
@@ -108,6 +119,7 @@ macro_rules! impl_command_new_str_optstr {
 }
 
 impl Command {
+    impl_command_new_str!(Acct, new_acct);
     impl_command_new_str!(Cwd, new_cwd);
     impl_command_new_str!(Dele, new_dele);
     impl_command_new_optstr!(Lang, new_lang);
@@ -117,6 +129,7 @@ impl Command {
     impl_command_new_optstr!(Mlst, new_mlst);
     impl_command_new_optstr!(Mlsd, new_mlsd);
     impl_command_new_str!(Mdtm, new_mdtm);
+    impl_command_new_str!(Open, new_open);
     impl_command_new_str_optstr!(Opts, new_opts);
     impl_command_new_str!(Pass, new_pass);
     impl_command_new_str!(RenameFrom, new_rename_from);
@@ -131,11 +144,12 @@ impl Command {
 }
 
 #[cfg(feature = "_secure")]
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[allow(unused)]
-/// Protection level; argument for `Prot` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Protection level of the data connections; argument for `Prot` command
 pub enum ProtectionLevel {
+    /// Data is transferred in clear
     Clear,
+    /// Data connections are secured with TLS
     Private,
 }
 
@@ -145,6 +159,7 @@ impl ToString for Command {
     fn to_string(&self) -> String {
         let mut s = match self {
             Self::Abor => "ABOR".to_string(),
+            Self::Acct(a) => format!("ACCT {}", a),
             Self::Appe(f) => format!("APPE {}", f),
             #[cfg(feature = "_secure")]
             Self::Auth => "AUTH TLS".to_string(),
@@ -153,6 +168,15 @@ impl ToString for Command {
             Self::ClearCommandChannel => "CCC".to_string(),
             Self::Cwd(d) => format!("CWD {}", d),
             Self::Dele(f) => format!("DELE {}", f),
+            Self::Eprt(addr) => {
+                let protocol = match addr {
+                    SocketAddr::V4(_) => 1,
+                    SocketAddr::V6(_) => 2,
+                };
+                format!("EPRT |{}|{}|{}|", protocol, addr.ip(), addr.port())
+            },
+            Self::Epsv => "EPSV".to_string(),
+            Self::EpsvAll => "EPSV ALL".to_string(),
             Self::Feat => "FEAT".to_string(),
             Self::Lang(l) => match l {
                 Some(l) => format!("LANG {}", l),
@@ -177,6 +201,7 @@ impl ToString for Command {
                 .map(|x| format!("NLST {}", x))
                 .unwrap_or_else(|| "NLST".to_string()),
             Self::Noop => "NOOP".to_string(),
+            Self::Open(h) => format!("OPEN {}", h),
             Self::Opts(s, s2) => match s2 {
                 Some(s2) => format!("OPTS {} {}", s, s2),
                 None => format!("OPTS {}", s),
@@ -231,6 +256,14 @@ mod test {
     #[test]
     fn should_stringify_command() {
         assert_eq!(Command::Abor.to_string().as_str(), "ABOR\r\n");
+        assert_eq!(
+            Command::Acct(String::from("omar")).to_string().as_str(),
+            "ACCT omar\r\n"
+        );
+        assert_eq!(
+            Command::Open(String::from("ftp.example.com")).to_string().as_str(),
+            "OPEN ftp.example.com\r\n"
+        );
         assert_eq!(
             Command::Appe(String::from("foobar.txt"))
                 .to_string()
@@ -250,6 +283,20 @@ mod test {
             Command::Dele(String::from("a.txt")).to_string().as_str(),
             "DELE a.txt\r\n"
         );
+        assert_eq!(
+            Command::Eprt("132.235.1.2:6275".parse().unwrap())
+                .to_string()
+                .as_str(),
+            "EPRT |1|132.235.1.2|6275|\r\n"
+        );
+        assert_eq!(
+            Command::Eprt("[1080::8:800:200c:417a]:5282".parse().unwrap())
+                .to_string()
+                .as_str(),
+            "EPRT |2|1080::8:800:200c:417a|5282|\r\n"
+        );
+        assert_eq!(Command::Epsv.to_string().as_str(), "EPSV\r\n");
+        assert_eq!(Command::EpsvAll.to_string().as_str(), "EPSV ALL\r\n");
         assert_eq!(
             Command::List(Some(String::from("/tmp")))
                 .to_string()
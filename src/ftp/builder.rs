@@ -0,0 +1,332 @@
+//! # Builder
+//!
+//! This module exposes the builder used to declare how an FTP session must be set up
+
+maybe_async_cfg::content! {
+
+#![maybe_async_cfg::default(
+    idents(
+        TlsBackend,
+        FtpStream,
+        FtpStreamBuilder,
+    ),
+)]
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use super::FtpStream;
+#[cfg(feature = "_secure")]
+use crate::command::ProtectionLevel;
+#[cfg(feature = "_secure")]
+use crate::types::FtpError;
+use crate::types::{
+    ActiveModeConfig, FileType, FtpProxy, FtpResult, KeepAliveConfig, Mode, PassiveAddressPolicy, PassiveTargetPolicy,
+    Proxy,
+};
+
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
+use super::TlsBackend;
+
+use std::fmt;
+#[cfg(feature = "_secure")]
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Declarative configuration of an FTP session.
+/// `connect()` establishes the connection and applies every setting, returning a ready session.
+/// The builder can be cloned and reused to re-create sessions with the same settings.
+#[maybe_async_cfg::maybe(
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")),
+    async(feature="async", replace_feature("_secure", "_async-tls")),
+)]
+#[derive(Clone)]
+pub struct FtpStreamBuilder {
+    addr: String,
+    proxy: Option<Proxy>,
+    ftp_proxy: Option<FtpProxy>,
+    #[cfg(feature = "_secure")]
+    tls: Option<(Arc<dyn TlsBackend>, String)>,
+    #[cfg(feature = "_secure")]
+    implicit_tls: bool,
+    #[cfg(feature = "_secure")]
+    protection_level: ProtectionLevel,
+    credentials: Option<(String, String)>,
+    transfer_type: Option<FileType>,
+    mode: Mode,
+    active_mode_config: ActiveModeConfig,
+    passive_address_policy: PassiveAddressPolicy,
+    passive_target_policy: PassiveTargetPolicy,
+    working_directory: Option<String>,
+    connect_timeout: Option<Duration>,
+    control_timeout: Option<Duration>,
+    data_timeout: Option<Duration>,
+    keep_alive: KeepAliveConfig,
+}
+
+#[maybe_async_cfg::maybe(
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")),
+    async(feature="async", replace_feature("_secure", "_async-tls")),
+)]
+impl FtpStreamBuilder {
+    /// Create a builder for a session with the server at `addr` (port 21 is used if missing)
+    pub fn new<S: Into<String>>(addr: S) -> Self {
+        Self {
+            addr: addr.into(),
+            proxy: None,
+            ftp_proxy: None,
+            #[cfg(feature = "_secure")]
+            tls: None,
+            #[cfg(feature = "_secure")]
+            implicit_tls: false,
+            #[cfg(feature = "_secure")]
+            protection_level: ProtectionLevel::Private,
+            credentials: None,
+            transfer_type: None,
+            mode: Mode::Passive,
+            active_mode_config: ActiveModeConfig::default(),
+            passive_address_policy: PassiveAddressPolicy::default(),
+            passive_target_policy: PassiveTargetPolicy::default(),
+            working_directory: None,
+            connect_timeout: None,
+            control_timeout: None,
+            data_timeout: None,
+            keep_alive: KeepAliveConfig::default(),
+        }
+    }
+
+    /// Tunnel the control and data connections through `proxy`
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Log in through an FTP proxy (gateway); the address of the builder must be the one of the FTP proxy
+    pub fn ftp_proxy(mut self, ftp_proxy: FtpProxy) -> Self {
+        self.ftp_proxy = Some(ftp_proxy);
+        self
+    }
+
+    /// Switch to explicit FTPS (`AUTH TLS`) with the provided TLS backend, verifying the server certificate for `domain`
+    #[cfg(feature = "_secure")]
+    pub fn secure<C: TlsBackend + 'static, S: Into<String>>(mut self, tls_connector: C, domain: S) -> Self {
+        self.tls = Some((Arc::new(tls_connector), domain.into()));
+        self.implicit_tls = false;
+        self
+    }
+
+    /// Use implicit FTPS with the provided TLS backend, verifying the server certificate for `domain`:
+    /// the connection is secured right away (see `FtpStream::connect_secure_implicit`); port 990 is used if missing
+    #[cfg(feature = "_secure")]
+    pub fn secure_implicit<C: TlsBackend + 'static, S: Into<String>>(mut self, tls_connector: C, domain: S) -> Self {
+        self.tls = Some((Arc::new(tls_connector), domain.into()));
+        self.implicit_tls = true;
+        self
+    }
+
+    /// Set the protection level of the data connections of a secure session (see `FtpStream::set_protection_level`);
+    /// data connections are secured by default
+    #[cfg(feature = "_secure")]
+    pub fn protection_level(mut self, level: ProtectionLevel) -> Self {
+        self.protection_level = level;
+        self
+    }
+
+    /// Log in with the provided credentials
+    pub fn login<U: Into<String>, P: Into<String>>(mut self, user: U, password: P) -> Self {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    /// Set the transfer type once logged in
+    pub fn transfer_type(mut self, file_type: FileType) -> Self {
+        self.transfer_type = Some(file_type);
+        self
+    }
+
+    /// Set the data connection mode
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set the active mode configuration (see `FtpStream::set_active_mode_config`)
+    pub fn active_mode_config(mut self, config: ActiveModeConfig) -> Self {
+        self.active_mode_config = config;
+        self
+    }
+
+    /// Set the passive address policy (see `FtpStream::set_passive_address_policy`)
+    pub fn passive_address_policy(mut self, policy: PassiveAddressPolicy) -> Self {
+        self.passive_address_policy = policy;
+        self
+    }
+
+    /// Set the passive target policy (see `FtpStream::set_passive_target_policy`)
+    pub fn passive_target_policy(mut self, policy: PassiveTargetPolicy) -> Self {
+        self.passive_target_policy = policy;
+        self
+    }
+
+    /// Change the working directory once logged in
+    pub fn working_directory<S: Into<String>>(mut self, path: S) -> Self {
+        self.working_directory = Some(path.into());
+        self
+    }
+
+    /// Set the connect timeout (see `FtpStream::connect_timeout`)
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the control timeout (see `FtpStream::set_control_timeout`)
+    pub fn control_timeout(mut self, timeout: Duration) -> Self {
+        self.control_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the data timeout (see `FtpStream::set_data_timeout`)
+    pub fn data_timeout(mut self, timeout: Duration) -> Self {
+        self.data_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the keep-alive configuration (see `FtpStream::set_keep_alive`)
+    pub fn keep_alive(mut self, config: KeepAliveConfig) -> Self {
+        self.keep_alive = config;
+        self
+    }
+
+    /// Returns the transfer type set with `transfer_type`, if any
+    pub(crate) fn get_transfer_type(&self) -> Option<&FileType> {
+        self.transfer_type.as_ref()
+    }
+
+    /// Connect to the server and set up the session
+    pub async fn connect(&self) -> FtpResult<FtpStream> {
+        let mut stream = match (&self.proxy, self.connect_timeout) {
+            #[cfg(feature = "_secure")]
+            (Some(_), _) if self.implicit_tls => {
+                return Err(FtpError::SecureError("implicit FTPS is not supported through a proxy".to_string()));
+            },
+            #[cfg(feature = "_secure")]
+            (None, timeout) if self.implicit_tls => {
+                let (tls_connector, domain) = self.tls.as_ref().expect("TLS is configured in implicit mode");
+                FtpStream::connect_secure_implicit_shared(self.addr.as_str(), tls_connector.clone(), domain, timeout)
+                    .await?
+            },
+            (Some(proxy), timeout) => {
+                FtpStream::connect_with_proxy_timeout(self.addr.as_str(), proxy.clone(), timeout).await?
+            },
+            (None, Some(timeout)) => FtpStream::connect_timeout(self.addr.as_str(), timeout).await?,
+            (None, None) => FtpStream::connect(self.addr.as_str()).await?,
+        };
+        stream.set_control_timeout(self.control_timeout)?;
+        stream.set_data_timeout(self.data_timeout);
+        stream.set_keep_alive(self.keep_alive)?;
+        stream.set_active_mode_config(self.active_mode_config.clone());
+        stream.set_passive_address_policy(self.passive_address_policy);
+        stream.set_passive_target_policy(self.passive_target_policy.clone());
+        stream.set_mode(self.mode);
+        stream.set_ftp_proxy(self.ftp_proxy.clone());
+
+        #[cfg(feature = "_secure")]
+        {
+            match &self.tls {
+                Some((tls_connector, domain)) if !self.implicit_tls => {
+                    stream = stream.into_secure_shared(tls_connector.clone(), domain).await?;
+                },
+                _ => {},
+            }
+            if self.tls.is_some() && self.protection_level != ProtectionLevel::Private {
+                stream.set_protection_level(self.protection_level).await?;
+            }
+        }
+        if let Some((user, password)) = &self.credentials {
+            stream.login(user.as_str(), password.as_str()).await?;
+        }
+        if let Some(file_type) = &self.transfer_type {
+            stream.transfer_type(file_type.clone()).await?;
+        }
+        if let Some(path) = &self.working_directory {
+            stream.cwd(path).await?;
+        }
+
+        Ok(stream)
+    }
+}
+
+#[maybe_async_cfg::maybe(
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")),
+    async(feature="async", replace_feature("_secure", "_async-tls")),
+)]
+impl fmt::Debug for FtpStreamBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("FtpStreamBuilder");
+        debug
+            .field("addr", &self.addr)
+            .field("proxy", &self.proxy)
+            .field("ftp_proxy", &self.ftp_proxy);
+        #[cfg(feature = "_secure")]
+        debug
+            .field("tls", &self.tls)
+            .field("implicit_tls", &self.implicit_tls)
+            .field("protection_level", &self.protection_level);
+        debug
+            // The password is never printed
+            .field("credentials", &self.credentials.as_ref().map(|(user, _)| (user, "***")))
+            .field("transfer_type", &self.transfer_type)
+            .field("mode", &self.mode)
+            .field("active_mode_config", &self.active_mode_config)
+            .field("passive_address_policy", &self.passive_address_policy)
+            .field("passive_target_policy", &self.passive_target_policy)
+            .field("working_directory", &self.working_directory)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("control_timeout", &self.control_timeout)
+            .field("data_timeout", &self.data_timeout)
+            .field("keep_alive", &self.keep_alive)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::mock::{Action, MockServer};
+
+    use pretty_assertions::assert_eq;
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_set_up_session() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("USER test", Action::Reply("331 Password required")),
+            ("PASS secret", Action::Reply("230 Logged in")),
+            ("TYPE I", Action::Reply("200 Type set to I")),
+            ("CWD /pub", Action::Reply("250 Directory changed")),
+            ("EPSV", Action::Epsv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let builder = FtpStreamBuilder::new(server.addr().to_string())
+            .login("test", "secret")
+            .transfer_type(FileType::Binary)
+            .working_directory("/pub")
+            .mode(Mode::ExtendedPassive)
+            .connect_timeout(Duration::from_secs(5));
+        let mut stream = builder.clone().connect().await.unwrap();
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_not_print_password() {
+        let builder = FtpStreamBuilder::new("127.0.0.1:21").login("test", String::from("secret"));
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains("\"test\""));
+        assert!(!debug.contains("secret"));
+    }
+}
+
+}
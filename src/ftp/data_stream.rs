@@ -6,23 +6,25 @@ maybe_async_cfg::content! {
 
 #![maybe_async_cfg::default(
     idents(
-        async_native_tls(sync="native_tls", async), 
         async_std(sync="std", async), 
         Read(use), 
         Result(use), 
         Write(use), 
         TcpStream(use),
-        TlsStream(use),
         DataStream,
         DataStreamProj,
         TlsStreamWrapper,
         TlsStreamWrapperProj,
+        TransportStream,
     ),
 )]
 
-#[maybe_async_cfg::maybe(sync(feature = "sync-secure"), async(feature = "async-secure"))]
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
 use super::tls_stream::TlsStreamWrapper;
 
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use super::transport::TransportStream;
+
 #[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
 use async_std::{
     io::{Read, Result, Write},
@@ -35,63 +37,82 @@ use pin_project::pin_project;
 use std::pin::Pin;
 
 
-/// Data Stream used for communications. It can be both of type Tcp in case of plain communication or Tls in case of FTPS
+/// Data Stream used for communications. It can be both of type Tcp in case of plain communication or Tls in case of FTPS,
+/// or a stream of a custom transport
 #[maybe_async_cfg::maybe(
-    sync(feature = "sync", replace_feature("_secure", "sync-secure"), drop_attrs(pin)), 
-    async(feature = "async", replace_feature("_secure", "async-secure"), inner("pin_project(project = DataStreamProjAsync)")),
+    sync(feature = "sync", replace_feature("_secure", "_sync-tls"), drop_attrs(pin)), 
+    async(feature = "async", replace_feature("_secure", "_async-tls"), inner("pin_project(project = DataStreamProjAsync)")),
 )]
 #[derive(Debug)]
 pub enum DataStream {
     Tcp(#[pin] TcpStream),
     #[cfg(feature = "_secure")]
     Tls(#[pin] TlsStreamWrapper),
+    Custom(Box<dyn TransportStream>),
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature = "sync", replace_feature("_secure", "sync-secure")), 
-    async(feature = "async", replace_feature("_secure", "async-secure")),
+    sync(feature = "sync", replace_feature("_secure", "_sync-tls")), 
+    async(feature = "async", replace_feature("_secure", "_async-tls")),
 )]
 impl DataStream {
     /// Unwrap the stream into TcpStream. This method is only used in secure connection.
-    pub fn into_tcp_stream(self) -> TcpStream {
+    /// Fails with an `Unsupported` io error if the stream belongs to a custom transport.
+    pub fn into_tcp_stream(self) -> Result<TcpStream> {
         match self {
-            DataStream::Tcp(stream) => stream,
+            DataStream::Tcp(stream) => Ok(stream),
             #[cfg(feature = "_secure")]
-            DataStream::Tls(stream) => stream.tcp_stream(),
+            DataStream::Tls(stream) => Ok(stream.tcp_stream()),
+            DataStream::Custom(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "custom transport streams have no underlying TcpStream",
+            )),
         }
     }
 
-    /// Returns a reference to the underlying TcpStream.
-    pub fn get_ref(&self) -> &TcpStream {
+    /// Returns a reference to the underlying TcpStream, if the stream doesn't belong to a custom transport
+    pub fn get_ref(&self) -> Option<&TcpStream> {
         match self {
-            DataStream::Tcp(ref stream) => stream,
+            DataStream::Tcp(ref stream) => Some(stream),
             #[cfg(feature = "_secure")]
-            DataStream::Tls(ref stream) => stream.get_ref(),
+            DataStream::Tls(ref stream) => Some(stream.get_ref()),
+            DataStream::Custom(_) => None,
         }
     }
-}
 
+    /// Close the TLS session, if any, keeping the TCP connection open.
+    /// Returns once the peer has closed its side of the session as well.
+    pub(crate) async fn shutdown_tls(&mut self) -> Result<()> {
+        match self {
+            #[cfg(feature = "_secure")]
+            DataStream::Tls(ref mut stream) => stream.shutdown().await,
+            _ => Ok(()),
+        }
+    }
+}
 
 // -- sync
 
-#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "sync-secure")))]
+#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "_sync-tls")))]
 impl Read for DataStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         match self {
             DataStream::Tcp(ref mut stream) => stream.read(buf),
             #[cfg(feature = "_secure")]
             DataStream::Tls(ref mut stream) => stream.read(buf),
+            DataStream::Custom(ref mut stream) => stream.read(buf),
         }
     }
 }
 
-#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "sync-secure")))]
+#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "_sync-tls")))]
 impl Write for DataStream {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         match self {
             DataStream::Tcp(ref mut stream) => stream.write(buf),
             #[cfg(feature = "_secure")]
             DataStream::Tls(ref mut stream) => stream.write(buf),
+            DataStream::Custom(ref mut stream) => stream.write(buf),
         }
     }
 
@@ -100,13 +121,14 @@ impl Write for DataStream {
             DataStream::Tcp(ref mut stream) => stream.flush(),
             #[cfg(feature = "_secure")]
             DataStream::Tls(ref mut stream) => stream.flush(),
+            DataStream::Custom(ref mut stream) => stream.flush(),
         }
     }
 }
 
 // -- sync
 
-#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "async-secure")))]
+#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "_async-tls")))]
 impl Read for DataStream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -117,11 +139,12 @@ impl Read for DataStream {
             DataStreamProj::Tcp(stream) => stream.poll_read(cx, buf),
             #[cfg(feature = "_secure")]
             DataStreamProj::Tls(stream) => stream.poll_read(cx, buf),
+            DataStreamProj::Custom(stream) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
 
-#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "async-secure")))]
+#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "_async-tls")))]
 impl Write for DataStream {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -132,6 +155,7 @@ impl Write for DataStream {
             DataStreamProj::Tcp(stream) => stream.poll_write(cx, buf),
             #[cfg(feature = "_secure")]
             DataStreamProj::Tls(stream) => stream.poll_write(cx, buf),
+            DataStreamProj::Custom(stream) => Pin::new(stream).poll_write(cx, buf),
         }
     }
 
@@ -143,6 +167,7 @@ impl Write for DataStream {
             DataStreamProj::Tcp(stream) => stream.poll_flush(cx),
             #[cfg(feature = "_secure")]
             DataStreamProj::Tls(stream) => stream.poll_flush(cx),
+            DataStreamProj::Custom(stream) => Pin::new(stream).poll_flush(cx),
         }
     }
 
@@ -154,6 +179,7 @@ impl Write for DataStream {
             DataStreamProj::Tcp(stream) => stream.poll_close(cx),
             #[cfg(feature = "_secure")]
             DataStreamProj::Tls(stream) => stream.poll_close(cx),
+            DataStreamProj::Custom(stream) => Pin::new(stream).poll_close(cx),
         }
     }
 }
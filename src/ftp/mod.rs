@@ -20,20 +20,44 @@ maybe_async_cfg::content! {
         TlsConnector(use),
         Cursor(use),
         DataStream,
+        TlsStreamWrapper,
         TlsCtx,
+        TlsBackend,
+        TlsBackendStream,
         FtpStream,
         FtpStreamInternals,
+        FtpStreamBuilder,
+        ReconnectingFtpStream,
+        FtpPool,
+        PooledFtpStream,
+        ActiveListener,
+        Transport,
+        TransportStream,
+        TransportListener,
+        socks5_connect(fn),
+        socks5_bind(fn),
+        socks5_accept(fn),
+        http_connect(fn),
         test_setup_stream(fn),
         test_finalize_stream(fn),
         test_tls_connector(fn),
     )
 )]
 
+mod tls;
 mod tls_stream;
 mod data_stream;
+mod builder;
+mod proxy;
+mod transport;
+mod reconnect;
+mod pool;
 use super::utils::*;
 
-use super::types::{FileType, FtpError, FtpResult, Mode, Response};
+use super::types::{
+    ActiveModeConfig, FileType, FtpError, FtpProxy, FtpProxyKind, FtpResult, KeepAliveConfig, Mode, PassiveAddressPolicy,
+    PassiveTargetPolicy, Proxy, ProxyCredentials, Response,
+};
 use super::Status;
 //use crate::callbacks;
 use crate::command::Command;
@@ -45,14 +69,36 @@ use crate::command::ProtectionLevel;
 
 #[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
 use data_stream::DataStream;
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
+use tls_stream::TlsStreamWrapper;
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub use builder::FtpStreamBuilder;
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub use reconnect::ReconnectingFtpStream;
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub use pool::{FtpPool, PooledFtpStream};
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use proxy::{http_connect, socks5_accept, socks5_bind, socks5_connect};
+use proxy::{ProxyCtx, TargetAddr};
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub use transport::{Transport, TransportListener, TransportStream};
 
-#[maybe_async_cfg::maybe(sync(feature = "sync-secure"), async(feature = "async-secure"))]
-use async_native_tls::TlsConnector;
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
+pub use tls::{TlsBackend, TlsBackendStream};
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+pub use tls::RustlsConnector;
+#[cfg(feature = "_secure")]
+pub use tls::{CertificatePin, CertificatePinSet, CertificateVerifier, ClientIdentity, TlsInfo, VerifyingTlsBackend};
 
 #[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
 use async_std::{
-    io::{copy, BufReader, Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
 };
 
 #[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
@@ -63,37 +109,125 @@ use std::io::BufRead;
 
 #[cfg(feature = "async")]
 use async_std::{prelude::*};
+#[cfg(feature = "async")]
+use std::future::Future;
 
 use chrono::offset::TimeZone;
 use chrono::{DateTime, Utc};
-use std::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::string::String;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Some data for TLS mode
-#[maybe_async_cfg::maybe(sync(feature="sync-secure"), async(feature="async-secure"))]
+#[maybe_async_cfg::maybe(sync(feature="_sync-tls"), async(feature="_async-tls"))]
 #[derive(Debug)]
 pub struct TlsCtx {
-    pub tls_connector: TlsConnector,
+    pub tls_connector: Arc<dyn TlsBackend>,
     pub domain: String,
+    /// Protection level of the data connections negotiated with `PROT`
+    pub protection: ProtectionLevel,
+    /// TLS session of the last secured data connection
+    pub last_data_tls_info: Option<TlsInfo>,
+}
+
+/// Listener waiting for the data connection in active mode
+#[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
+enum ActiveListener {
+    /// Local listener
+    Direct(TcpListener),
+    /// Connection to a SOCKS5 proxy which received a `BIND` request
+    Socks5(TcpStream),
+    /// Listener of a custom transport
+    Custom(Box<dyn TransportListener>),
+}
+
+#[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
+impl ActiveListener {
+    /// Wait no longer than `timeout` for the server to open the data connection.
+    /// If `peer` is set, connections to the local listener coming from other hosts are dropped.
+    async fn accept(self, peer: Option<IpAddr>, timeout: Option<Duration>) -> FtpResult<DataStream> {
+        match self {
+            Self::Direct(listener) => {
+                let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                loop {
+                    let (stream, addr) = Self::accept_until(&listener, deadline).await?;
+                    match peer {
+                        Some(peer) if canonical_ip(addr.ip()) != canonical_ip(peer) => {
+                            warn!("Rejected data connection from {}: expected {}", addr, peer);
+                        },
+                        _ => return Ok(DataStream::Tcp(stream)),
+                    }
+                }
+            },
+            Self::Socks5(mut stream) => {
+                FtpStream::with_timeout(timeout, socks5_accept(&mut stream)).await?;
+                Ok(DataStream::Tcp(stream))
+            },
+            Self::Custom(listener) => Ok(DataStream::Custom(FtpStream::with_timeout(timeout, listener.accept()).await?)),
+        }
+    }
+
+    /// Accept a connection, failing if `deadline` expires first
+    #[maybe_async_cfg::only_if(sync)]
+    fn accept_until(listener: &TcpListener, deadline: Option<Instant>) -> FtpResult<(TcpStream, SocketAddr)> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Ok(listener.accept()?),
+        };
+        listener.set_nonblocking(true)?;
+        let result = loop {
+            match listener.accept() {
+                Ok((stream, addr)) => break stream.set_nonblocking(false).map(|_| (stream, addr)),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        debug!("No data connection before the accept timeout");
+                        break Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+                    }
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                },
+                Err(err) => break Err(err),
+            }
+        };
+        listener.set_nonblocking(false)?;
+        Ok(result?)
+    }
+
+    /// Accept a connection, failing if `deadline` expires first
+    #[maybe_async_cfg::only_if(async)]
+    async fn accept_until(listener: &TcpListener, deadline: Option<Instant>) -> FtpResult<(TcpStream, SocketAddr)> {
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        FtpStream::with_timeout(timeout, listener.accept()).await
+    }
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature="sync", replace_feature("_secure", "sync-secure")), 
-    async(feature="async", replace_feature("_secure", "async-secure")), 
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")), 
+    async(feature="async", replace_feature("_secure", "_async-tls")), 
 )]
 #[derive(Debug)]
 pub struct FtpStreamInternals {
     skip450: bool,
+    epsv_unsupported: bool,
+    epsv_all: bool,
+    last_activity: Instant,
+    transfer_in_progress: bool,
+    pending_noops: usize,
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature="sync", replace_feature("_secure", "sync-secure")), 
-    async(feature="async", replace_feature("_secure", "async-secure")), 
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")), 
+    async(feature="async", replace_feature("_secure", "_async-tls")), 
 )]
 impl FtpStreamInternals {
     fn new() -> Self {
         Self {
             skip450: false,
+            epsv_unsupported: false,
+            epsv_all: false,
+            last_activity: Instant::now(),
+            transfer_in_progress: false,
+            pending_noops: 0,
         }
     }
 
@@ -104,18 +238,44 @@ impl FtpStreamInternals {
     fn set_skip_450(&mut self) {
         self.skip450 = true;
     }
+
+    fn set_epsv_unsupported(&mut self) {
+        self.epsv_unsupported = true;
+    }
+
+    fn set_epsv_all(&mut self) {
+        self.epsv_all = true;
+    }
+
+    fn is_idle_for(&self, interval: Duration) -> bool {
+        self.last_activity.elapsed() >= interval
+    }
+
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
 }
 
 /// Stream to interface with the FTP server. This interface is only for the command stream.
 #[maybe_async_cfg::maybe(
-    sync(feature="sync", replace_feature("_secure", "sync-secure")), 
-    async(feature="async", replace_feature("_secure", "async-secure")), 
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")), 
+    async(feature="async", replace_feature("_secure", "_async-tls")), 
 )]
 #[derive(Debug)]
 pub struct FtpStream {
     internals: FtpStreamInternals,
     reader: BufReader<DataStream>,
     mode: Mode,
+    active_mode_config: ActiveModeConfig,
+    passive_address_policy: PassiveAddressPolicy,
+    passive_target_policy: PassiveTargetPolicy,
+    connect_timeout: Option<Duration>,
+    control_timeout: Option<Duration>,
+    data_timeout: Option<Duration>,
+    proxy_ctx: Option<ProxyCtx>,
+    ftp_proxy: Option<FtpProxy>,
+    transport: Option<Arc<dyn Transport>>,
+    keep_alive_config: KeepAliveConfig,
     #[cfg(feature = "_secure")]
     tls_ctx: Option<TlsCtx>,
     #[cfg(feature = "_with-welcome-msg")]
@@ -125,21 +285,70 @@ pub struct FtpStream {
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature="sync", replace_feature("_secure", "sync-secure")), 
-    async(feature="async", replace_feature("_secure", "async-secure")), 
+    sync(feature="sync", replace_feature("_secure", "_sync-tls")), 
+    async(feature="async", replace_feature("_secure", "_async-tls")), 
 )]
 impl FtpStream {
     /// Creates an FTP Stream.
     pub async fn connect<A: ToSocketAddrsWithDefaultPort, #[cfg(feature = "support-ftpclient")] Client: FtpClient>(addr: A) -> FtpResult<Self> {
         debug!("Connecting to server");
+        let stream = Self::connect_stream(addr, 21, None).await?;
+        Self::connect_with_stream(DataStream::Tcp(stream), None, None).await
+    }
+
+    /// Creates an FTP Stream, failing if the connection can't be established within `timeout`.
+    /// The timeout also applies to the welcome message and, afterwards, to the connection of data channels
+    /// in passive mode (see `set_connect_timeout`).
+    pub async fn connect_timeout<A: ToSocketAddrsWithDefaultPort>(addr: A, timeout: Duration) -> FtpResult<Self> {
+        debug!("Connecting to server (timeout: {:?})", timeout);
+        let stream = Self::connect_stream(addr, 21, Some(timeout)).await?;
+        Self::connect_with_stream(DataStream::Tcp(stream), Some(timeout), None).await
+    }
+
+    /// Creates an FTP Stream tunneled through `proxy`. `addr` is `host[:port]`; host names are resolved by the proxy.
+    /// All the data connections of the session are tunneled through the proxy too.
+    pub async fn connect_with_proxy(addr: &str, proxy: Proxy) -> FtpResult<Self> {
+        Self::connect_with_proxy_timeout(addr, proxy, None).await
+    }
+
+    /// Creates an FTP Stream tunneled through `proxy`, failing if the tunnel can't be established within `timeout`
+    pub(crate) async fn connect_with_proxy_timeout(addr: &str, proxy: Proxy, timeout: Option<Duration>) -> FtpResult<Self> {
+        let proxy_ctx = ProxyCtx { proxy, server: TargetAddr::parse(addr, 21)? };
+        debug!("Connecting to server {} through proxy {}", proxy_ctx.server, proxy_ctx.addr());
+        let stream = Self::connect_proxy(&proxy_ctx, &proxy_ctx.server, timeout).await?;
+        Self::connect_with_stream(DataStream::Tcp(stream), timeout, Some(proxy_ctx)).await
+    }
+
+    /// Creates an FTP Stream over a custom transport, which opens the control connection and all the data connections.
+    /// TLS is not available on custom transports.
+    pub async fn connect_with_transport(transport: Arc<dyn Transport>) -> FtpResult<Self> {
+        debug!("Connecting to server through transport {:?}", transport);
+        let stream = transport.connect_control().await?;
+        let mut ftp_stream = Self::connect_with_stream(DataStream::Custom(stream), None, None).await?;
+        ftp_stream.transport = Some(transport);
+        Ok(ftp_stream)
+    }
 
-        let stream = TcpStream::connect(addr.with_default_port(21)).await?;
-        debug!("Established connection with server");
+    /// Initialize the FTP stream on an established control connection and read the welcome message
+    async fn connect_with_stream(stream: DataStream, connect_timeout: Option<Duration>, proxy_ctx: Option<ProxyCtx>) -> FtpResult<Self> {
+        if let Some(stream) = stream.get_ref() {
+            debug!("Established connection with server {}", stream.peer_addr()?);
+        }
 
         let mut ftp_stream = Self {
             internals: FtpStreamInternals::new(),
-            reader: BufReader::new(DataStream::Tcp(stream)),
+            reader: BufReader::new(stream),
             mode: Mode::Passive,
+            active_mode_config: ActiveModeConfig::default(),
+            passive_address_policy: PassiveAddressPolicy::default(),
+            passive_target_policy: PassiveTargetPolicy::default(),
+            connect_timeout,
+            control_timeout: None,
+            data_timeout: None,
+            proxy_ctx,
+            ftp_proxy: None,
+            transport: None,
+            keep_alive_config: KeepAliveConfig::default(),
             #[cfg(feature = "_secure")]
             tls_ctx: None,
             #[cfg(feature = "_with-welcome-msg")]
@@ -149,9 +358,12 @@ impl FtpStream {
         };
 
         debug!("Reading server response...");
+        // The welcome message is part of the connection, so wait for it no longer than the connect timeout
+        ftp_stream.set_control_timeout(connect_timeout)?;
         #[allow(unused_variables)]
         let response = ftp_stream.read_response_in(&[Status::Ready]).await?;
         debug!("Server READY; response: {}", response.body);
+        ftp_stream.set_control_timeout(None)?;
 
         #[cfg(feature = "_with-welcome-msg")]
         {
@@ -172,18 +384,129 @@ impl FtpStream {
         self
     }
 
+    /// Enable active mode for data channel, using the provided listener configuration
+    pub fn active_mode_with_config(mut self, config: ActiveModeConfig) -> Self {
+        self.set_active_mode_config(config);
+        self.active_mode()
+    }
+
+    /// Set the configuration of the listener used by active mode (bind address, port range, advertised address)
+    pub fn set_active_mode_config(&mut self, config: ActiveModeConfig) {
+        debug!("Changed active mode configuration to {:?}", config);
+        self.active_mode_config = config;
+    }
+
     /// Set the data channel transfer mode
     pub fn set_mode(&mut self, mode: Mode) {
         debug!("Changed mode to {:?}", mode);
         self.mode = mode;
     }
 
+    /// Set which address is used to open the data connection in passive mode (`PASV`).
+    /// Use it when the server is behind NAT and returns its private address.
+    pub fn set_passive_address_policy(&mut self, policy: PassiveAddressPolicy) {
+        debug!("Changed passive address policy to {:?}", policy);
+        self.passive_address_policy = policy;
+    }
+
+    /// Set which data connection addresses returned by the server in passive mode are accepted (FTP bounce protection).
+    /// By default only the address of the control connection peer is accepted.
+    pub fn set_passive_target_policy(&mut self, policy: PassiveTargetPolicy) {
+        debug!("Changed passive target policy to {:?}", policy);
+        self.passive_target_policy = policy;
+    }
+
+    /// Set the FTP proxy (gateway) this stream is connected to. `login` will use its login style to reach the real host.
+    pub fn set_ftp_proxy(&mut self, ftp_proxy: Option<FtpProxy>) {
+        debug!("Changed FTP proxy to {:?}", ftp_proxy);
+        self.ftp_proxy = ftp_proxy;
+    }
+
+    /// Set the keep-alive configuration: NOOP commands sent on the control connection during transfers
+    /// and by `keep_alive`, and TCP keepalive on the control and data connections
+    pub fn set_keep_alive(&mut self, config: KeepAliveConfig) -> FtpResult<()> {
+        debug!("Changed keep-alive configuration to {:?}", config);
+        self.keep_alive_config = config;
+        if let Some(stream) = self.reader.get_ref().get_ref() {
+            self.apply_tcp_keepalive(stream)?;
+        }
+        Ok(())
+    }
+
+    /// Send a NOOP on the control connection if nothing has been sent for the configured NOOP interval
+    /// (see `set_keep_alive`); does nothing if the interval isn't configured.
+    ///
+    /// Call it periodically while the session is idle, or while reading or writing the streams returned by
+    /// `retr_as_stream`, `put_with_stream` and `append_with_stream`: during a transfer the reply isn't waited for,
+    /// and it's consumed when the transfer is finalized.
+    /// `put_file`, `append_file` and `retr` call it on their own.
+    pub async fn keep_alive(&mut self) -> FtpResult<()> {
+        let interval = match self.keep_alive_config.noop_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        if !self.internals.is_idle_for(interval) {
+            return Ok(());
+        }
+
+        if self.internals.transfer_in_progress {
+            debug!("Sending keep-alive NOOP during transfer");
+            self.perform(Command::Noop).await?;
+            self.internals.pending_noops += 1;
+            Ok(())
+        } else {
+            debug!("Sending keep-alive NOOP");
+            self.noop().await
+        }
+    }
+
+    /// Set the timeout for establishing data connections in passive mode. `None` means no timeout.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        debug!("Changed connect timeout to {:?}", timeout);
+        self.connect_timeout = timeout;
+    }
+
+    /// Set the maximum time to wait for a reply on the control connection (and to send a command).
+    /// `None` means no timeout. When the timeout expires, `FtpError::Timeout` is returned.
+    pub fn set_control_timeout(&mut self, timeout: Option<Duration>) -> FtpResult<()> {
+        debug!("Changed control timeout to {:?}", timeout);
+        self.control_timeout = timeout;
+        self.apply_control_timeout()
+    }
+
+    /// Set the maximum time a data connection can stay idle (no bytes read or written). `None` means no timeout.
+    ///
+    /// In the sync version the timeout is set on the data connection socket, so it applies to the streams returned
+    /// by `retr_as_stream`, `put_with_stream` and `append_with_stream` too; reads and writes fail with a
+    /// `TimedOut` or `WouldBlock` io error, which the transfers performed by the library (`put_file`, `append_file`,
+    /// `list`, `nlst`...) report as `FtpError::Timeout`.
+    /// In the async version the timeout applies to the transfers performed by the library (`put_file`, `append_file`,
+    /// `list`, `nlst`...) and to the data connection handshake; wrap your own reads and writes on the returned
+    /// streams with `async_std::io::timeout`.
+    pub fn set_data_timeout(&mut self, timeout: Option<Duration>) {
+        debug!("Changed data timeout to {:?}", timeout);
+        self.data_timeout = timeout;
+    }
+
+    /// Send `EPSV ALL` to the server (RFC 2428) and switch to extended passive mode.
+    /// Once accepted, the server will refuse any other data connection setup command
+    /// (PASV, PORT...), so the session never falls back to PASV.
+    /// Useful when the server sits behind a firewall which only handles EPSV.
+    pub async fn epsv_all(&mut self) -> FtpResult<()> {
+        debug!("Sending EPSV ALL");
+        self.command(Command::EpsvAll, &[Status::CommandOk]).await?;
+        self.internals.set_epsv_all();
+        self.set_mode(Mode::ExtendedPassive);
+        Ok(())
+    }
+
     /// Switch to a secure mode if possible, using a provided TLS configuration.
-    /// This method does nothing if the connect is already secured.
+    /// The session state (transfer mode, EPSV support, welcome message...) is kept.
     ///
-    /// ## Panics
+    /// ## Errors
     ///
-    /// Panics if the plain TCP connection cannot be switched to TLS mode.
+    /// Fails with `FtpError::SecureError` if the connection is already secured or runs over a custom transport,
+    /// and with the error of the TLS backend if the handshake fails.
     ///
     /// ## Example
     ///
@@ -200,38 +523,138 @@ impl FtpStream {
     /// let mut ftp_stream = ftp_stream.into_secure(ctx, "localhost").await.unwrap();
     /// # });
     /// ```
+    ///
+    /// Any `TlsBackend` can be used: a native-tls `TlsConnector` (`sync-secure`/`async-secure` features)
+    /// or a `RustlsConnector` built from a `rustls::ClientConfig` (`sync-rustls`/`async-rustls` features).
+    /// Data connections resume the TLS session of the control connection only with backends supporting it
+    /// (see `TlsBackend::for_session`): use rustls with servers requiring session reuse.
+    #[cfg(feature = "_secure")]
+    pub async fn into_secure<C: TlsBackend + 'static>(
+        self,
+        tls_connector: C,
+        domain: &str,
+    ) -> FtpResult<Self> {
+        self.into_secure_shared(Arc::new(tls_connector), domain).await
+    }
+
+    /// Switch to secure mode with a TLS connector shared with other sessions (e.g. by `FtpStreamBuilder`)
     #[cfg(feature = "_secure")]
-    pub async fn into_secure(
+    pub(crate) async fn into_secure_shared(
         mut self,
-        tls_connector: TlsConnector,
+        tls_connector: Arc<dyn TlsBackend>,
         domain: &str,
     ) -> FtpResult<Self> {
+        if self.transport.is_some() {
+            error!("TLS is not supported over custom transports");
+            return Err(FtpError::SecureError("TLS is not supported over custom transports".to_string()));
+        }
+        if self.tls_ctx.is_some() {
+            error!("The connection is already secured");
+            return Err(FtpError::SecureError("the connection is already secured".to_string()));
+        }
+
+        // Data connections must resume the TLS session of the control connection
+        let tls_connector = tls_connector.for_session().unwrap_or(tls_connector);
+
         // Ask the server to start securing data.
         debug!("Initializing TLS auth");
         self.command(Command::Auth, &[Status::AuthOk]).await?;
         debug!("TLS OK; initializing TLS stream");
 
-        let stream = tls_connector.connect(
-            domain, 
-            self.reader.into_inner().into_tcp_stream()
+        let stream = Self::with_timeout(
+            self.control_timeout,
+            tls_connector.connect(domain, self.reader.into_inner().into_tcp_stream()?),
         ).await?;
         debug!("TLS stream OK");
 
-        let mut secured_ftp_stream = Self {
-            internals: FtpStreamInternals::new(),
-            reader: BufReader::new(DataStream::Tls(stream.into())),
-            mode: self.mode,
-            tls_ctx: Some(TlsCtx{ tls_connector, domain: domain.into() }),
-            #[cfg(feature = "_with-welcome-msg")]
-            welcome_msg: self.welcome_msg,
-        };
+        // Session state (mode, welcome message, EPSV support...) is kept
+        self.reader = BufReader::new(DataStream::Tls(stream.into()));
+        // Data connections stay in clear until `PROT P` is accepted
+        self.tls_ctx = Some(TlsCtx{ tls_connector, domain: domain.into(), protection: ProtectionLevel::Clear, last_data_tls_info: None });
+
+        self.set_protection_level(ProtectionLevel::Private).await?;
+        Ok(self)
+    }
+
+    /// Creates an FTP Stream in implicit FTPS mode: the TLS handshake is performed as soon as the connection
+    /// is established, before the welcome message, and all the data connections are secured too.
+    /// Port 990 is used if `addr` has no port.
+    #[cfg(feature = "_secure")]
+    pub async fn connect_secure_implicit<A: ToSocketAddrsWithDefaultPort, C: TlsBackend + 'static>(
+        addr: A,
+        tls_connector: C,
+        domain: &str,
+    ) -> FtpResult<Self> {
+        Self::connect_secure_implicit_shared(addr, Arc::new(tls_connector), domain, None).await
+    }
+
+    /// Creates an FTP Stream in implicit FTPS mode with a TLS connector shared with other sessions,
+    /// failing if the connection and the handshake can't be completed within `timeout`
+    #[cfg(feature = "_secure")]
+    pub(crate) async fn connect_secure_implicit_shared<A: ToSocketAddrsWithDefaultPort>(
+        addr: A,
+        tls_connector: Arc<dyn TlsBackend>,
+        domain: &str,
+        timeout: Option<Duration>,
+    ) -> FtpResult<Self> {
+        debug!("Connecting to server (implicit FTPS)");
+        // Data connections must resume the TLS session of the control connection
+        let tls_connector = tls_connector.for_session().unwrap_or(tls_connector);
+        let stream = Self::connect_stream(addr, 990, timeout).await?;
+        Self::apply_handshake_timeout(&stream, timeout)?;
+        let stream = Self::with_timeout(timeout, tls_connector.connect(domain, stream)).await?;
+        debug!("TLS stream OK");
+
+        let mut ftp_stream = Self::connect_with_stream(DataStream::Tls(stream.into()), timeout, None).await?;
+        ftp_stream.tls_ctx = Some(TlsCtx{ tls_connector, domain: domain.into(), protection: ProtectionLevel::Clear, last_data_tls_info: None });
+        ftp_stream.set_protection_level(ProtectionLevel::Private).await?;
+        Ok(ftp_stream)
+    }
 
+    /// Change the protection level of the data connections (`PROT`): with `ProtectionLevel::Clear`
+    /// only the control connection is encrypted, and data is transferred in clear.
+    /// The protection buffer size (`PBSZ`) is negotiated again before `PROT`.
+    /// If the server refuses the new level, the previous one is kept.
+    ///
+    /// Fails with `FtpError::SecureError` if the session is not secured.
+    #[cfg(feature = "_secure")]
+    pub async fn set_protection_level(&mut self, level: ProtectionLevel) -> FtpResult<()> {
+        if self.tls_ctx.is_none() {
+            return Err(FtpError::SecureError("the data protection level can only be set on secure sessions".to_string()));
+        }
+        debug!("Setting data protection level to {:?}", level);
         // Set protection buffer size
-        secured_ftp_stream.command(Command::Pbsz(0), &[Status::CommandOk]).await?;
-        // Change the level of data protectio to Private
-        secured_ftp_stream.command(Command::Prot(ProtectionLevel::Private), &[Status::CommandOk]).await?;
+        self.command(Command::Pbsz(0), &[Status::CommandOk]).await?;
+        // Change the level of data protection
+        self.command(Command::Prot(level), &[Status::CommandOk]).await?;
+        if let Some(tls_ctx) = self.tls_ctx.as_mut() {
+            tls_ctx.protection = level;
+        }
+        Ok(())
+    }
 
-        Ok(secured_ftp_stream)
+    /// Returns the details of the TLS session of the control connection (protocol version, cipher suite,
+    /// server certificate chain and server name), or `None` if the control connection is not secured
+    /// (e.g. after `clear_command_channel`)
+    #[cfg(feature = "_secure")]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        let tls_ctx = self.tls_ctx.as_ref()?;
+        match self.reader.get_ref() {
+            DataStream::Tls(stream) => Some(stream.tls_info(tls_ctx.domain.as_str())),
+            _ => None,
+        }
+    }
+
+    /// Returns the details of the TLS session of the last secured data connection, if any
+    #[cfg(feature = "_secure")]
+    pub fn data_tls_info(&self) -> Option<&TlsInfo> {
+        self.tls_ctx.as_ref()?.last_data_tls_info.as_ref()
+    }
+
+    /// Returns the protection level of the data connections, or `None` if the session is not secured
+    #[cfg(feature = "_secure")]
+    pub fn protection_level(&self) -> Option<ProtectionLevel> {
+        self.tls_ctx.as_ref().map(|tls_ctx| tls_ctx.protection)
     }
 
     /// Returns welcome message retrieved from server (if available)
@@ -241,34 +664,117 @@ impl FtpStream {
     }
 
     /// Returns a reference to the underlying TcpStream.
-    pub async fn get_ref(&self) -> &TcpStream {
-        self.reader.get_ref().get_ref()
+    /// Fails if the session runs over a custom transport.
+    pub async fn get_ref(&self) -> FtpResult<&TcpStream> {
+        self.control_tcp_stream()
     }    
 
     /// Log in to the FTP server.
+    /// If an FTP proxy is set (see `set_ftp_proxy`), `user` and `password` are the credentials on the real host.
     pub async fn login<S: AsRef<str>>(&mut self, user: S, password: S) -> FtpResult<()> {
-        debug!("Signin in with user '{}'", user.as_ref());
-        let user_response = self.command(Command::new_user(user), &[Status::LoggedIn, Status::NeedPassword]).await?;
-        
-        if user_response.status == Status::NeedPassword {
-            debug!("Password is required");
-            self.command(Command::new_pass(password), &[Status::LoggedIn]).await?;
+        match self.ftp_proxy.clone() {
+            Some(ftp_proxy) => self.login_through_ftp_proxy(&ftp_proxy, user.as_ref(), password.as_ref()).await?,
+            None => self.user_pass(user.as_ref(), password.as_ref(), None).await?,
         }
 
         debug!("Login OK");
         Ok(())
     }
 
+    /// Perform the login sequence of the FTP proxy login style
+    async fn login_through_ftp_proxy(&mut self, ftp_proxy: &FtpProxy, user: &str, password: &str) -> FtpResult<()> {
+        debug!("Signin in to {} through FTP proxy ({:?})", ftp_proxy.host, ftp_proxy.kind);
+        let host = ftp_proxy.host.as_str();
+        match ftp_proxy.kind {
+            FtpProxyKind::UserAtHost => {
+                self.user_pass(&format!("{}@{}", user, host), password, None).await
+            },
+            FtpProxyKind::UserAtHostAfterLogin => {
+                let credentials = Self::ftp_proxy_credentials(ftp_proxy)?;
+                self.user_pass(&credentials.username, &credentials.password, None).await?;
+                self.user_pass(&format!("{}@{}", user, host), password, None).await
+            },
+            FtpProxyKind::Site | FtpProxyKind::Open => {
+                if let Some(ref credentials) = ftp_proxy.credentials {
+                    self.user_pass(&credentials.username, &credentials.password, None).await?;
+                }
+                let command = match ftp_proxy.kind {
+                    FtpProxyKind::Site => Command::new_site(host),
+                    _ => Command::new_open(host),
+                };
+                self.command(command, &[Status::CommandOk, Status::Ready, Status::LoggedIn]).await?;
+                self.user_pass(user, password, None).await
+            },
+            FtpProxyKind::ProxyUserAtHost => {
+                let credentials = Self::ftp_proxy_credentials(ftp_proxy)?;
+                self.user_pass(&format!("{}@{}", credentials.username, host), &credentials.password, None).await?;
+                self.user_pass(user, password, None).await
+            },
+            FtpProxyKind::UserAtProxyUserAtHost => {
+                let credentials = Self::ftp_proxy_credentials(ftp_proxy)?;
+                self.user_pass(
+                    &format!("{}@{}@{}", user, credentials.username, host),
+                    &format!("{}@{}", password, credentials.password),
+                    None,
+                ).await
+            },
+            FtpProxyKind::UserAtHostProxyUserAcct => {
+                let credentials = Self::ftp_proxy_credentials(ftp_proxy)?;
+                self.user_pass(
+                    &format!("{}@{} {}", user, host, credentials.username),
+                    password,
+                    Some(&credentials.password),
+                ).await
+            },
+        }
+    }
+
+    /// Returns the FTP proxy credentials, which are required by the login style
+    fn ftp_proxy_credentials(ftp_proxy: &FtpProxy) -> FtpResult<&ProxyCredentials> {
+        ftp_proxy.credentials.as_ref().ok_or_else(|| {
+            error!("FTP proxy login style {:?} requires proxy credentials", ftp_proxy.kind);
+            FtpError::ProxyError(format!("login style {:?} requires proxy credentials", ftp_proxy.kind))
+        })
+    }
+
+    /// Send USER and, if required, PASS; if the server asks for an account and `account` is provided, send ACCT
+    async fn user_pass(&mut self, user: &str, password: &str, account: Option<&str>) -> FtpResult<()> {
+        debug!("Signin in with user '{}'", user);
+        let logged_in: &[Status] = match account {
+            Some(_) => &[Status::LoggedIn, Status::LoginNeedAccount],
+            None => &[Status::LoggedIn],
+        };
+        let mut response = self.command(Command::new_user(user), &[Status::LoggedIn, Status::NeedPassword]).await?;
+
+        if response.status == Status::NeedPassword {
+            debug!("Password is required");
+            response = self.command(Command::new_pass(password), logged_in).await?;
+        }
+
+        if let (Status::LoginNeedAccount, Some(account)) = (response.status, account) {
+            debug!("Account is required");
+            self.command(Command::new_acct(account), &[Status::LoggedIn, Status::CommandNotImplemented]).await?;
+        }
+
+        Ok(())
+    }
+
     /// Perform clear command channel (CCC).
     /// Once the command is performed, the command channel will be encrypted no more.
     /// The data stream will still be secure.
+    /// The TLS session is closed with the `close_notify` alert; the one the server may send back is waited for
+    /// a short while, then the session goes on in clear anyway.
     #[cfg(feature = "_secure")]
     pub async fn clear_command_channel(mut self) -> FtpResult<Self> {
         // Ask the server to stop securing data
         debug!("performing clear command channel");
         self.command(Command::ClearCommandChannel, &[Status::CommandOk]).await?;
         trace!("CCC OK");
-        self.reader = BufReader::new(DataStream::Tcp(self.reader.into_inner().into_tcp_stream()));
+        let mut stream = self.reader.into_inner();
+        // Close the TLS session before going on in clear: the server answers our close_notify with its own,
+        // which must not be left on the connection
+        Self::with_timeout(self.control_timeout, stream.shutdown_tls()).await?;
+        self.reader = BufReader::new(DataStream::Tcp(stream.into_tcp_stream()?));
         Ok(self)
     }
 
@@ -359,6 +865,27 @@ impl FtpStream {
     /// }).is_ok());
     /// # assert!(conn.rm("retr.txt").is_ok());
     /// ```
+    ///
+    /// Keep-alive NOOPs are sent while `reader` reads the data stream (see `set_keep_alive`).
+    #[maybe_async_cfg::only_if(sync)]
+    pub fn retr<S, F, T>(&mut self, file_name: S, mut reader: F) -> FtpResult<T>
+    where
+        F: FnMut(&mut (dyn Read + std::marker::Unpin)) -> FtpResult<T>,
+        S: AsRef<str>,
+    {
+        let mut stream = self.retr_as_stream(file_name)?;
+
+        let result = reader(&mut KeepAliveReader { stream: &mut stream, ftp: self })?;
+        self.finalize_retr_stream(stream)?;
+        Ok(result)
+    }
+
+    /// The implementation of `RETR` command where `filename` is the name of the file
+    /// to download from FTP and `reader` is the function which operates with the
+    /// data stream opened.
+    ///
+    /// Keep-alive NOOPs are sent while `reader` reads the data stream (see `set_keep_alive`).
+    #[maybe_async_cfg::only_if(async)]
     pub async fn retr<S, F, T>(&mut self, file_name: S, mut reader: F) -> FtpResult<T>
     where
         F: FnMut(&mut (dyn Read + std::marker::Unpin)) -> FtpResult<T>,
@@ -372,8 +899,10 @@ impl FtpStream {
     {
         let mut stream = self.retr_as_stream(file_name).await?;
 
-        let result = reader(&mut stream)?;
-        self.finalize_retr_stream(stream).await?;
+        let mut keep_alive_reader = KeepAliveReaderAsync { stream: &mut stream, ftp: self, noop: None };
+        let result = reader(&mut keep_alive_reader)?;
+        keep_alive_reader.finish().await?;
+        self.finalize_retr_data_stream(stream).await?;
         Ok(result)
     }
 
@@ -398,7 +927,8 @@ impl FtpStream {
     /// This method is a more complicated way to retrieve a file.
     /// The reader returned should be dropped.
     /// Also you will have to read the response to make sure it has the correct value.
-    /// Once file has been read, call `finalize_retr_stream()`
+    /// Once file has been read, call `finalize_retr_data_stream()`, or `finalize_retr_stream()` if it has been wrapped
+    /// For long transfers, call `keep_alive()` periodically while reading (see `set_keep_alive`).
     pub async fn retr_as_stream<S: AsRef<str>>(&mut self, file_name: S) -> FtpResult<DataStream> {
         debug!("Retrieving '{}'", file_name.as_ref());
         let data_stream = self.data_command(Command::new_retr(file_name)).await?;
@@ -407,6 +937,21 @@ impl FtpStream {
     }
 
     /// Finalize retr stream; must be called once the requested file, got previously with `retr_as_stream()` has been read
+    #[maybe_async_cfg::only_if(sync)]
+    pub fn finalize_retr_stream(&mut self, stream: impl Read) -> FtpResult<()> {
+        debug!("Finalizing retr stream");
+        // Drop stream NOTE: must be done first, otherwise server won't return any response
+        drop(stream);
+        trace!("dropped stream");
+        // Then read response
+        self.read_response_in(&[Status::ClosingDataConnection, Status::RequestedFileActionOk])?;
+        Ok(())
+    }
+
+    /// Finalize retr stream; must be called once the requested file, got previously with `retr_as_stream()` has been read.
+    /// The stream is just dropped: use `finalize_retr_data_stream()` to shut secure data streams down
+    /// with the TLS `close_notify` alert.
+    #[maybe_async_cfg::only_if(async)]
     pub async fn finalize_retr_stream(&mut self, stream: impl Read) -> FtpResult<()> {
         debug!("Finalizing retr stream");
         // Drop stream NOTE: must be done first, otherwise server won't return any response
@@ -417,6 +962,31 @@ impl FtpStream {
         Ok(())
     }
 
+    /// Finalize the data stream returned by `retr_as_stream()`, once it has been read.
+    /// Dropping a sync secure data stream already sends the TLS `close_notify` alert.
+    #[maybe_async_cfg::only_if(sync)]
+    pub fn finalize_retr_data_stream(&mut self, stream: DataStream) -> FtpResult<()> {
+        self.finalize_retr_stream(stream)
+    }
+
+    /// Finalize the data stream returned by `retr_as_stream()`, once it has been read.
+    /// Secure data streams are shut down with the TLS `close_notify` alert before the response is read,
+    /// which `finalize_retr_stream()` can't do with an arbitrary reader.
+    #[maybe_async_cfg::only_if(async)]
+    pub async fn finalize_retr_data_stream(&mut self, mut stream: DataStream) -> FtpResult<()> {
+        debug!("Finalizing retr stream");
+        // Close stream NOTE: must be done first, otherwise server won't return any response
+        if let Err(err) = stream.close().await {
+            // The server may have closed the connection already
+            debug!("Failed to close data stream: {}", err);
+        }
+        drop(stream);
+        trace!("closed stream");
+        // Then read response
+        self.read_response_in(&[Status::ClosingDataConnection, Status::RequestedFileActionOk]).await?;
+        Ok(())
+    }
+
     /// Removes the remote pathname from the server.
     pub async fn rmdir<S: AsRef<str>>(&mut self, pathname: S) -> FtpResult<()> {
         debug!("Removing directory {}", pathname.as_ref());
@@ -442,7 +1012,7 @@ impl FtpStream {
         // Get stream
         let mut data_stream = self.put_with_stream(filename).await?;
 
-        let bytes = copy(r, &mut data_stream).await?;
+        let bytes = self.copy_data(r, &mut data_stream).await?;
 
         self.finalize_put_stream(data_stream).await?;
         Ok(bytes)
@@ -452,6 +1022,7 @@ impl FtpStream {
     /// The returned stream must be then correctly manipulated to write the content of the source file to the remote destination
     /// The stream must be then correctly dropped.
     /// Once you've finished the write, YOU MUST CALL THIS METHOD: `finalize_put_stream`
+    /// For long transfers, call `keep_alive()` periodically while writing (see `set_keep_alive`).
     pub async fn put_with_stream<S: AsRef<str>>(&mut self, filename: S) -> FtpResult<DataStream> {
         debug!("Put file {}", filename.as_ref());
         let data_stream = self.data_command(Command::new_store(filename)).await?;
@@ -462,18 +1033,39 @@ impl FtpStream {
     /// Finalize put when using stream
     /// This method must be called once the file has been written and
     /// `put_with_stream` has been used to write the file
-    pub async fn finalize_put_stream(&mut self, stream: impl Write) -> FtpResult<()> {
+    #[maybe_async_cfg::only_if(sync)]
+    pub fn finalize_put_stream(&mut self, stream: impl Write) -> FtpResult<()> {
         debug!("Finalizing put stream");
         // Drop stream NOTE: must be done first, otherwise server won't return any response
         drop(stream);
         trace!("Stream dropped");
         // Read response
+        self.read_response_in(&[Status::ClosingDataConnection, Status::RequestedFileActionOk])?;
+        Ok(())
+    }
+
+    /// Finalize put when using stream
+    /// This method must be called once the file has been written and
+    /// `put_with_stream` has been used to write the file.
+    /// The stream is flushed and closed; secure data streams are shut down with the TLS `close_notify` alert,
+    /// which servers expect to consider the upload complete.
+    #[maybe_async_cfg::only_if(async)]
+    pub async fn finalize_put_stream(&mut self, stream: impl Write) -> FtpResult<()> {
+        debug!("Finalizing put stream");
+        // Close stream NOTE: must be done first, otherwise server won't return any response
+        if let Err(err) = Box::pin(stream).close().await {
+            // The reply of the server tells whether the upload is complete
+            error!("Failed to close data stream: {}", err);
+        }
+        trace!("Stream closed");
+        // Read response
         self.read_response_in(&[Status::ClosingDataConnection, Status::RequestedFileActionOk]).await?;
         Ok(())
     }
 
     /// Open specified file for appending data. Returns the stream to append data to specified file.
     /// Once you've finished the write, YOU MUST CALL THIS METHOD: `finalize_put_stream`
+    /// For long transfers, call `keep_alive()` periodically while writing (see `set_keep_alive`).
     pub async fn append_with_stream<S: AsRef<str>>(&mut self, filename: S) -> FtpResult<DataStream> {
         debug!("Appending to file {}", filename.as_ref());
         let stream = self.data_command(Command::Appe(filename.as_ref().to_string())).await?;
@@ -489,7 +1081,7 @@ impl FtpStream {
         // Get stream
         let mut data_stream = self.append_with_stream(filename).await?;
 
-        let bytes = copy(r, &mut data_stream).await?;
+        let bytes = self.copy_data(r, &mut data_stream).await?;
 
         self.finalize_put_stream(Box::new(data_stream)).await?;
         Ok(bytes)
@@ -663,12 +1255,12 @@ impl FtpStream {
     // -- private
 
     /// Retrieve stream "message"
-    async fn get_lines_from_stream(data_stream: &mut BufReader<DataStream>) -> FtpResult<Vec<String>> {
+    async fn get_lines_from_stream(data_stream: &mut BufReader<DataStream>, timeout: Option<Duration>) -> FtpResult<Vec<String>> {
         let mut lines: Vec<String> = Vec::new();
 
         loop {
             let mut line = String::new();
-            match data_stream.read_line(&mut line).await {
+            match Self::with_timeout(timeout, data_stream.read_line(&mut line)).await {
                 Ok(0) => break,
                 Ok(_) => {
                     if line.ends_with('\n') {
@@ -682,6 +1274,7 @@ impl FtpStream {
                     }
                     lines.push(line);
                 }
+                Err(FtpError::Timeout) => return Err(FtpError::Timeout),
                 Err(_) => return Err(FtpError::BadResponse),
             }
         }
@@ -697,6 +1290,57 @@ impl FtpStream {
 
     /// Retrieve single line response
     async fn read_response_in(&mut self, expected_status: &[Status]) -> FtpResult<Response> {
+        let mut response = self.read_response().await?;
+
+        // Skip the replies to the keep-alive NOOPs sent during the transfer
+        while self.internals.pending_noops > 0
+            && response.status == Status::CommandOk
+            && !expected_status.contains(&Status::CommandOk)
+        {
+            trace!("Skipping reply to keep-alive NOOP");
+            self.internals.pending_noops -= 1;
+            response = self.read_response().await?;
+        }
+
+        // A final reply ends the transfer (if any)
+        if response.status.code() >= 200 {
+            self.internals.transfer_in_progress = false;
+        }
+        // Servers may reply to the keep-alive NOOPs once the transfer is complete
+        if matches!(response.status, Status::ClosingDataConnection | Status::RequestedFileActionOk) {
+            while self.internals.pending_noops > 0 {
+                self.internals.pending_noops -= 1;
+                let noop_response = self.read_response().await?;
+                trace!("Skipping reply to keep-alive NOOP");
+                if noop_response.status != Status::CommandOk {
+                    return Err(FtpError::UnexpectedResponse(noop_response));
+                }
+            }
+        }
+
+        // Return Ok or error with response
+        let status = response.status;
+        if expected_status.contains(&status) {
+            Ok(response)
+        } else {
+            let err = match status {
+                Status::BadCommand | Status::NotImplemented | Status::BadSequence => {
+                    FtpError::BadCommand{ status, message: response.body_into_inline_result()? }
+                },
+                Status::BadArguments | Status::NotImplementedParameter => {
+                    FtpError::BadParameter{ status, message: response.body_into_inline_result()? }
+                },
+                _ => {
+                    debug!("Bad status: {:?}", status);
+                    FtpError::UnexpectedResponse(response)
+                }
+            };
+            Err(err)
+        }
+    }
+
+    /// Read a single reply from the control connection
+    async fn read_response(&mut self) -> FtpResult<Response> {
         let mut line_buffer = String::new();
         let mut line = self.read_line(&mut line_buffer).await?;
 
@@ -765,24 +1409,7 @@ impl FtpStream {
             }
         };
 
-        // Return Ok or error with response
-        if expected_status.contains(&status) {
-            Ok(response)
-        } else {
-            let err = match status {
-                Status::BadCommand | Status::NotImplemented | Status::BadSequence => {
-                    FtpError::BadCommand{ status, message: response.body_into_inline_result()? }
-                },
-                Status::BadArguments | Status::NotImplementedParameter => {
-                    FtpError::BadParameter{ status, message: response.body_into_inline_result()? }
-                },
-                _ => {
-                    debug!("Bad status: {:?}", status);
-                    FtpError::UnexpectedResponse(response)
-                }
-            };
-            Err(err)
-        }
+        Ok(response)
     }
 
     /// Write data to stream with command to perform
@@ -791,7 +1418,8 @@ impl FtpStream {
         trace!("CC OUT: {}", command.trim_end_matches("\r\n"));
 
         let stream = self.reader.get_mut();
-        stream.write_all(command.as_bytes()).await?;
+        Self::with_timeout(self.control_timeout, stream.write_all(command.as_bytes())).await?;
+        self.internals.touch();
         Ok(())
     }
 
@@ -803,28 +1431,52 @@ impl FtpStream {
 
     /// Execute command which send data back in a separate stream
     async fn data_command(&mut self, cmd: Command) -> FtpResult<DataStream> {
-        let stream = match self.mode {
+        let stream = match self.data_mode()? {
             Mode::Passive => {
                 let addr = self.pasv().await?;
                 self.perform(cmd).await?;
-                TcpStream::connect(addr).await?
+                self.internals.transfer_in_progress = true;
+                self.connect_data(TargetAddr::Ip(addr)).await?
             },
-            Mode::Active => {
-                let listener = self.active().await?;
+            Mode::ExtendedPassive => {
+                let addr = self.epsv_or_pasv().await?;
                 self.perform(cmd).await?;
-                let (stream, _) = listener.accept().await?;
-                stream
+                self.internals.transfer_in_progress = true;
+                self.connect_data(addr).await?
+            },
+            mode @ (Mode::Active | Mode::ExtendedActive) => {
+                let listener = self.active(mode).await?;
+                self.perform(cmd).await?;
+                self.internals.transfer_in_progress = true;
+                let peer = match &listener {
+                    ActiveListener::Direct(_) if !self.active_mode_config.allow_any_peer => Some(self.peer_addr()?.ip()),
+                    _ => None,
+                };
+                listener.accept(peer, self.active_mode_config.accept_timeout).await?
             }
         };
 
+        let stream = match stream {
+            DataStream::Tcp(stream) => stream,
+            // Custom transports are responsible of their own timeouts and don't support TLS
+            stream => return Ok(stream),
+        };
+        self.apply_data_timeout(&stream)?;
+        self.apply_tcp_keepalive(&stream)?;
+
         #[cfg(feature = "_secure")]
         {
             match self.tls_ctx {
-                Some(ref tls_ctx) => {
-                    let tls_stream = tls_ctx.tls_connector.connect(tls_ctx.domain.as_str(), stream).await?;
-                    Ok(DataStream::Tls(tls_stream.into()))
+                Some(ref mut tls_ctx) if tls_ctx.protection == ProtectionLevel::Private => {
+                    let tls_stream = Self::with_timeout(
+                        self.data_timeout,
+                        tls_ctx.tls_connector.connect(tls_ctx.domain.as_str(), stream),
+                    ).await?;
+                    let tls_stream = TlsStreamWrapper::from(tls_stream);
+                    tls_ctx.last_data_tls_info = Some(tls_stream.tls_info(tls_ctx.domain.as_str()));
+                    Ok(DataStream::Tls(tls_stream))
                 },
-                None => {
+                _ => {
                     Ok(DataStream::Tcp(stream))
                 },
             }
@@ -837,31 +1489,443 @@ impl FtpStream {
 
     }
 
-    /// Create a new tcp listener and send a PORT command for it
-    async fn active(&mut self) -> FtpResult<TcpListener> {
-        debug!("Starting local tcp listener...");
-        let listener = TcpListener::bind("0.0.0.0:0").await?;
+    /// Resolve `addr` (using `default_port` if it has no port) and connect to the first address which accepts the connection
+    async fn connect_stream<A: ToSocketAddrsWithDefaultPort>(
+        addr: A,
+        default_port: u16,
+        timeout: Option<Duration>,
+    ) -> FtpResult<TcpStream> {
+        let addrs: Vec<SocketAddr> = addr.with_default_port(default_port).to_socket_addrs().await?.collect();
+        Self::connect_addrs(interleave_addrs(addrs), timeout).await
+    }
+
+    /// Race connections to `addrs` (RFC 8305): an attempt is started every `CONNECTION_ATTEMPT_DELAY`,
+    /// or as soon as the previous one fails, and the first established connection is returned.
+    /// If all the attempts fail, the error of each address is returned.
+    ///
+    /// Attempts are non-blocking connects checked every `CONNECT_POLL_INTERVAL`, so the attempts still
+    /// in progress are closed as soon as a connection is established.
+    #[maybe_async_cfg::only_if(sync)]
+    fn connect_addrs(addrs: Vec<SocketAddr>, timeout: Option<Duration>) -> FtpResult<TcpStream> {
+        if addrs.len() == 1 {
+            return Self::connect_addr(addrs[0], timeout);
+        }
+
+        let mut addrs = addrs.into_iter();
+        // Attempts in progress, with the time they were started at
+        let mut attempts: Vec<(SocketAddr, socket2::Socket, Instant)> = Vec::new();
+        let mut errors = Vec::new();
+        let mut next_attempt = Instant::now();
+        loop {
+            if attempts.is_empty() || Instant::now() >= next_attempt {
+                if let Some(addr) = addrs.next() {
+                    trace!("Connecting to {}", addr);
+                    match start_connect(addr) {
+                        Ok(socket) => attempts.push((addr, socket, Instant::now())),
+                        Err(err) => {
+                            debug!("Failed to connect to {}: {}", addr, err);
+                            errors.push((addr, err.into()));
+                            continue;
+                        }
+                    }
+                    next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+                }
+            }
+            if attempts.is_empty() {
+                break;
+            }
+
+            let mut index = 0;
+            while index < attempts.len() {
+                let (addr, socket, started) = &attempts[index];
+                let result = match poll_connect(socket) {
+                    Ok(false) if timeout.map(|timeout| started.elapsed() >= timeout).unwrap_or(false) => {
+                        Err(FtpError::Timeout)
+                    },
+                    Ok(connected) => Ok(connected),
+                    Err(err) => Err(err.into()),
+                };
+                match result {
+                    Ok(false) => index += 1,
+                    Ok(true) => {
+                        let (addr, socket, _) = attempts.swap_remove(index);
+                        trace!("Connected to {}", addr);
+                        socket.set_nonblocking(false)?;
+                        return Ok(socket.into());
+                    },
+                    Err(err) => {
+                        debug!("Failed to connect to {}: {}", addr, err);
+                        let (addr, _, _) = attempts.swap_remove(index);
+                        errors.push((addr, err));
+                        // Start the next attempt right away
+                        next_attempt = Instant::now();
+                    },
+                }
+            }
+            if !attempts.is_empty() {
+                std::thread::sleep(CONNECT_POLL_INTERVAL);
+            }
+        }
+
+        Err(Self::connect_error(errors))
+    }
+
+    /// Race connections to `addrs` (RFC 8305): an attempt is started every `CONNECTION_ATTEMPT_DELAY`,
+    /// or as soon as the previous one fails, and the first established connection is returned.
+    /// If all the attempts fail, the error of each address is returned.
+    ///
+    /// Attempts are polled by the calling task, so the attempts still in progress are dropped,
+    /// closing their sockets, as soon as a connection is established.
+    #[maybe_async_cfg::only_if(async)]
+    async fn connect_addrs(addrs: Vec<SocketAddr>, timeout: Option<Duration>) -> FtpResult<TcpStream> {
+        if addrs.len() == 1 {
+            return Self::connect_addr(addrs[0], timeout).await;
+        }
+
+        let mut addrs = addrs.into_iter();
+        // Attempts in progress; they must be `Send` for the connection futures to be spawnable
+        let mut attempts: Vec<std::pin::Pin<Box<dyn Future<Output = (SocketAddr, FtpResult<TcpStream>)> + Send>>> =
+            Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            if let Some(addr) = addrs.next() {
+                trace!("Connecting to {}", addr);
+                attempts.push(Box::pin(async move { (addr, Self::connect_addr(addr, timeout).await) }));
+            }
+            if attempts.is_empty() {
+                break;
+            }
+
+            // Resolves with the result of the first attempt which completes, removing it
+            let next_result = std::future::poll_fn(|cx| {
+                for index in 0..attempts.len() {
+                    if let std::task::Poll::Ready(result) = attempts[index].as_mut().poll(cx) {
+                        attempts.swap_remove(index);
+                        return std::task::Poll::Ready(result);
+                    }
+                }
+                std::task::Poll::Pending
+            });
+            let result = if addrs.len() > 0 {
+                match async_std::future::timeout(CONNECTION_ATTEMPT_DELAY, next_result).await {
+                    Ok(result) => result,
+                    Err(_) => continue,
+                }
+            } else {
+                next_result.await
+            };
+            match result {
+                (addr, Ok(stream)) => {
+                    trace!("Connected to {}", addr);
+                    return Ok(stream);
+                },
+                (addr, Err(err)) => {
+                    debug!("Failed to connect to {}: {}", addr, err);
+                    errors.push((addr, err));
+                }
+            }
+        }
+
+        Err(Self::connect_error(errors))
+    }
+
+    /// Error returned when no connection attempt succeeded
+    fn connect_error(mut errors: Vec<(SocketAddr, FtpError)>) -> FtpError {
+        match errors.len() {
+            0 => FtpError::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )),
+            1 => errors.remove(0).1,
+            _ => FtpError::ConnectFailed(errors),
+        }
+    }
+
+    /// Open a data connection towards `addr`, through the proxy or the custom transport if the session uses one
+    async fn connect_data(&self, addr: TargetAddr) -> FtpResult<DataStream> {
+        if let Some(ref transport) = self.transport {
+            let stream = transport.connect_data(addr.ip(), addr.port()).await?;
+            return Ok(DataStream::Custom(stream));
+        }
+
+        let stream = match (&self.proxy_ctx, addr) {
+            (Some(proxy_ctx), addr) => Self::connect_proxy(proxy_ctx, &addr, self.connect_timeout).await?,
+            (None, TargetAddr::Ip(addr)) => Self::connect_addr(addr, self.connect_timeout).await?,
+            (None, TargetAddr::ControlHost(port)) => {
+                Self::connect_addr(SocketAddr::new(self.peer_addr()?.ip(), port), self.connect_timeout).await?
+            },
+            (None, addr) => Self::connect_stream(addr.to_string().as_str(), 21, self.connect_timeout).await?,
+        };
+        Ok(DataStream::Tcp(stream))
+    }
+
+    /// Connect to the proxy and ask it to open a tunnel towards `target`
+    async fn connect_proxy(proxy_ctx: &ProxyCtx, target: &TargetAddr, timeout: Option<Duration>) -> FtpResult<TcpStream> {
+        let mut stream = Self::connect_stream(proxy_ctx.addr(), proxy_ctx.default_port(), timeout).await?;
+        match &proxy_ctx.proxy {
+            Proxy::Socks5 { credentials, .. } => {
+                Self::with_timeout(timeout, socks5_connect(&mut stream, credentials.as_ref(), target)).await?;
+            },
+            Proxy::Http { credentials, .. } => {
+                Self::with_timeout(timeout, http_connect(&mut stream, credentials.as_ref(), target)).await?;
+            },
+        }
+        Ok(stream)
+    }
+
+    /// Connect to `addr`, failing if the connection can't be established within `timeout`
+    #[maybe_async_cfg::only_if(sync)]
+    fn connect_addr(addr: SocketAddr, timeout: Option<Duration>) -> FtpResult<TcpStream> {
+        match timeout {
+            Some(timeout) => Self::with_timeout(Some(timeout), TcpStream::connect_timeout(&addr, timeout)),
+            None => Ok(TcpStream::connect(addr)?),
+        }
+    }
+
+    /// Connect to `addr`, failing if the connection can't be established within `timeout`
+    #[maybe_async_cfg::only_if(async)]
+    async fn connect_addr(addr: SocketAddr, timeout: Option<Duration>) -> FtpResult<TcpStream> {
+        Self::with_timeout(timeout, TcpStream::connect(addr)).await
+    }
+
+    /// Wait for `future` no longer than `timeout`
+    #[maybe_async_cfg::only_if(async)]
+    async fn with_timeout<F, T, E>(timeout: Option<Duration>, future: F) -> FtpResult<T>
+    where
+        F: Future<Output = Result<T, E>>,
+        FtpError: From<E>,
+    {
+        match timeout {
+            Some(timeout) => match async_std::future::timeout(timeout, future).await {
+                Ok(result) => Ok(result?),
+                Err(_) => {
+                    debug!("Operation timed out after {:?}", timeout);
+                    Err(FtpError::Timeout)
+                },
+            },
+            None => Ok(future.await?),
+        }
+    }
+
+    /// In the sync version timeouts are set on sockets, so this just converts the result,
+    /// turning the expiration of the socket timeout into `FtpError::Timeout`
+    #[maybe_async_cfg::only_if(sync)]
+    fn with_timeout<T, E>(_timeout: Option<Duration>, result: Result<T, E>) -> FtpResult<T>
+    where
+        FtpError: From<E>,
+    {
+        result.map_err(|err| FtpError::from(err).socket_timeout())
+    }
+
+    /// Set `timeout` on the socket, so that the TLS handshake of implicit FTPS doesn't wait forever
+    #[cfg(feature = "_secure")]
+    #[maybe_async_cfg::only_if(sync)]
+    fn apply_handshake_timeout(stream: &TcpStream, timeout: Option<Duration>) -> FtpResult<()> {
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// In the async version the handshake is wrapped in `with_timeout`
+    #[cfg(feature = "_secure")]
+    #[maybe_async_cfg::only_if(async)]
+    fn apply_handshake_timeout(_stream: &TcpStream, _timeout: Option<Duration>) -> FtpResult<()> {
+        Ok(())
+    }
+
+    /// Set the control timeout on the control connection socket
+    #[maybe_async_cfg::only_if(sync)]
+    fn apply_control_timeout(&self) -> FtpResult<()> {
+        // Custom transports are responsible of their own timeouts
+        if let Some(stream) = self.reader.get_ref().get_ref() {
+            stream.set_read_timeout(self.control_timeout)?;
+            stream.set_write_timeout(self.control_timeout)?;
+        }
+        Ok(())
+    }
+
+    /// In the async version the control timeout is applied with timers on each operation
+    #[maybe_async_cfg::only_if(async)]
+    fn apply_control_timeout(&self) -> FtpResult<()> {
+        Ok(())
+    }
+
+    /// Set the data idle timeout on a data connection socket
+    #[maybe_async_cfg::only_if(sync)]
+    fn apply_data_timeout(&self, stream: &TcpStream) -> FtpResult<()> {
+        stream.set_read_timeout(self.data_timeout)?;
+        stream.set_write_timeout(self.data_timeout)?;
+        Ok(())
+    }
+
+    /// In the async version the data timeout is applied with timers on each operation
+    #[maybe_async_cfg::only_if(async)]
+    fn apply_data_timeout(&self, _stream: &TcpStream) -> FtpResult<()> {
+        Ok(())
+    }
+
+    /// Copy `reader` into the data stream `writer`, failing if a write doesn't complete within the data timeout.
+    /// Keep-alive NOOPs are sent on the control connection meanwhile.
+    async fn copy_data<R, W>(&mut self, reader: &mut R, writer: &mut W) -> FtpResult<u64>
+    where
+        R: Read + std::marker::Unpin + ?Sized,
+        W: Write + std::marker::Unpin + ?Sized,
+    {
+        let mut buffer = vec![0; 65536];
+        let mut bytes: u64 = 0;
+        loop {
+            let size = match reader.read(&mut buffer).await {
+                Ok(0) => return Ok(bytes),
+                Ok(size) => size,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            Self::with_timeout(self.data_timeout, writer.write_all(&buffer[..size])).await?;
+            bytes += size as u64;
+            self.keep_alive().await?;
+        }
+    }
+
+    /// Enable TCP keepalive on `stream`, if configured
+    fn apply_tcp_keepalive(&self, stream: &TcpStream) -> FtpResult<()> {
+        if let Some(time) = self.keep_alive_config.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(time);
+            socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the mode to use for the next data connection.
+    /// PASV and PORT only support IPv4, so the extended modes are always used on IPv6 connections.
+    fn data_mode(&self) -> FtpResult<Mode> {
+        let ipv6 = matches!(self.server_addr()?.ip(), Some(IpAddr::V6(_)));
+        Ok(match self.mode {
+            Mode::Passive if ipv6 => Mode::ExtendedPassive,
+            Mode::Active if ipv6 => Mode::ExtendedActive,
+            mode => mode,
+        })
+    }
+
+    /// Create a new tcp listener and send a PORT (or EPRT in extended active mode) command for it
+    async fn active(&mut self, mode: Mode) -> FtpResult<ActiveListener> {
+        if let Some(proxy_ctx) = self.proxy_ctx.clone() {
+            return self.active_through_proxy(mode, &proxy_ctx).await;
+        }
+        if let Some(transport) = self.transport.clone() {
+            let (addr, listener) = transport.listen_data().await?;
+            debug!("Active mode, transport listening on {}", addr);
+            self.send_active_address(mode, addr).await?;
+            return Ok(ActiveListener::Custom(listener));
+        }
+
+        let local_ip = self.local_addr()?.ip();
+        // If not configured, the listener must have the same address family of the control connection
+        let bind_ip = match self.active_mode_config.bind_address {
+            Some(ip) => ip,
+            None => match local_ip {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            },
+        };
+
+        debug!("Starting local tcp listener...");
+        let listener = self.bind_active_listener(bind_ip).await?;
 
         let addr = listener.local_addr()?;
         trace!("Local address is {}", addr);
 
-        let tcp_stream = match self.reader.get_ref() {
-            DataStream::Tcp(stream) => stream,
+        // Advertise external ip, then bind address, then the local address of the control connection
+        let ip = match self.active_mode_config.external_ip {
+            Some(ip) => ip,
+            None if !bind_ip.is_unspecified() => bind_ip,
+            None => local_ip,
+        };
+        let addr = SocketAddr::new(ip, addr.port());
+        debug!("Active mode, listening on {}", addr);
+        self.send_active_address(mode, addr).await?;
 
-            #[cfg(feature = "_secure")]
-            DataStream::Tls(stream) => stream.get_ref(),
+        Ok(ActiveListener::Direct(listener))
+    }
+
+    /// Ask the proxy to wait for the data connection, and send the address the proxy is listening on to the server
+    async fn active_through_proxy(&mut self, mode: Mode, proxy_ctx: &ProxyCtx) -> FtpResult<ActiveListener> {
+        let credentials = match &proxy_ctx.proxy {
+            Proxy::Socks5 { credentials, .. } => credentials,
+            Proxy::Http { .. } => {
+                error!("Active mode is not supported through an HTTP proxy");
+                return Err(FtpError::ProxyError("active mode is not supported through an HTTP proxy".to_string()));
+            },
         };
-        let ip = tcp_stream.local_addr().unwrap().ip();
 
-        let msb = addr.port() / 256;
-        let lsb = addr.port() % 256;
-        let ip_port = format!("{},{},{}", ip.to_string().replace(".", ","), msb, lsb);
-        debug!("Active mode, listening on {}:{}", ip, addr.port());
+        debug!("Requesting a listener to proxy {}", proxy_ctx.addr());
+        let mut stream = Self::connect_stream(proxy_ctx.addr(), proxy_ctx.default_port(), self.connect_timeout).await?;
+        let addr = Self::with_timeout(
+            self.connect_timeout,
+            socks5_bind(&mut stream, credentials.as_ref(), &proxy_ctx.server),
+        ).await?;
+        debug!("Active mode, proxy listening on {}", addr);
+        self.send_active_address(mode, addr).await?;
 
-        debug!("Running PORT command");
-        self.command(Command::Port(ip_port), &[Status::CommandOk]).await?;
+        Ok(ActiveListener::Socks5(stream))
+    }
 
-        Ok(listener)
+    /// Send the address to connect to for the data connection with PORT or EPRT
+    async fn send_active_address(&mut self, mode: Mode, addr: SocketAddr) -> FtpResult<()> {
+        let command = match mode {
+            Mode::ExtendedActive => {
+                debug!("Running EPRT command");
+                Command::Eprt(addr)
+            },
+            _ => {
+                debug!("Running PORT command");
+                Command::Port(Self::port_argument(addr)?)
+            }
+        };
+        self.command(command, &[Status::CommandOk]).await?;
+        Ok(())
+    }
+
+    /// Bind the active mode listener to `ip`; if a port range is configured, the first free port in range is used
+    async fn bind_active_listener(&self, ip: IpAddr) -> FtpResult<TcpListener> {
+        let ports = match self.active_mode_config.port_range {
+            Some(ref range) => range.clone(),
+            None => 0..=0,
+        };
+
+        for port in ports {
+            match TcpListener::bind(SocketAddr::new(ip, port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => {
+                    trace!("Port {} is busy; trying next one", port);
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        error!("No free port available for active mode listener");
+        Err(FtpError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            "no free port available in active mode port range",
+        )))
+    }
+
+    /// Format address as PORT command argument (h1,h2,h3,h4,p1,p2).
+    /// PORT only supports IPv4 addresses: use EPRT for IPv6.
+    fn port_argument(addr: SocketAddr) -> FtpResult<String> {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                let [oct1, oct2, oct3, oct4] = ip.octets();
+                let (msb, lsb) = (addr.port() / 256, addr.port() % 256);
+                Ok(format!("{},{},{},{},{},{}", oct1, oct2, oct3, oct4, msb, lsb))
+            },
+            IpAddr::V6(_) => {
+                error!("PORT command doesn't support IPv6 address {}", addr);
+                Err(FtpError::ConnectionError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "PORT command requires an IPv4 address; use extended active mode",
+                )))
+            },
+        }
     }
     
     /// Runs the PASV command.
@@ -885,19 +1949,105 @@ impl FtpStream {
             caps[6].parse::<u8>().unwrap(),
         );
         let port = ((msb as u16) << 8) + lsb as u16;
-        let addr = format!("{}.{}.{}.{}:{}", oct1, oct2, oct3, oct4, port);
-        
-        trace!("Passive address: {}", addr);
-        let addr = SocketAddr::from_str(&addr)?;
+        let reply_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(oct1, oct2, oct3, oct4)), port);
+        trace!("Passive address: {}", reply_addr);
+
+        // The policies can't be applied if the proxy resolved the server host name
+        let server_ip = self.server_addr()?.ip();
+        let addr = match server_ip {
+            Some(server_ip) => self.passive_address_policy.resolve(reply_addr, server_ip),
+            None => reply_addr,
+        };
+        if addr != reply_addr {
+            debug!("Passive address {} replaced with {}", reply_addr, addr);
+        }
+        if let Err(err) = self.passive_target_policy.check(addr, server_ip) {
+            error!("Passive address {} refused by the passive target policy", addr);
+            return Err(err);
+        }
 
         Ok(addr)
     }
 
+    /// Runs the EPSV command.
+    /// The data connection must be opened towards the server of the control connection.
+    async fn epsv(&mut self) -> FtpResult<TargetAddr> {
+        debug!("EPSV command");
+        let response = self.command(Command::Epsv, &[Status::ExtendedPassiveMode]).await?;
+        let body = response.body_as_inline_result()?;
+
+        // EPSV response format: 229 Entering Extended Passive Mode (|||port|).
+        let caps = EPSV_PORT_RE.captures(body).ok_or_else(|| FtpError::UnexpectedResponse(response.clone()))?;
+
+        // All the delimiters must be the same character
+        let delim = &caps[1];
+        if &caps[2] != delim || &caps[3] != delim || &caps[5] != delim {
+            return Err(FtpError::UnexpectedResponse(response.clone()));
+        }
+        let port = caps[4].parse::<u16>().map_err(|_| FtpError::UnexpectedResponse(response.clone()))?;
+        let addr = self.server_addr()?.with_port(port);
+        if let Some(ip) = addr.ip() {
+            self.passive_target_policy.check(SocketAddr::new(ip, port), Some(ip))?;
+        }
+
+        trace!("Extended passive address: {}", addr);
+        Ok(addr)
+    }
+
+    /// Runs the EPSV command; if the server doesn't implement it, falls back to PASV.
+    /// The fallback happens once: EPSV is never tried again for this session.
+    /// There's no fallback on IPv6 connections, since PASV only supports IPv4.
+    async fn epsv_or_pasv(&mut self) -> FtpResult<TargetAddr> {
+        if !self.internals.epsv_unsupported {
+            let can_fall_back = !self.internals.epsv_all && !matches!(self.server_addr()?.ip(), Some(IpAddr::V6(_)));
+            match self.epsv().await {
+                Err(FtpError::BadCommand{ status, .. })
+                    if can_fall_back && matches!(status, Status::BadCommand | Status::NotImplemented) =>
+                {
+                    debug!("EPSV is not supported by server (status {}); falling back to PASV", status.code());
+                    self.internals.set_epsv_unsupported();
+                },
+                result => return result,
+            }
+        }
+
+        Ok(TargetAddr::Ip(self.pasv().await?))
+    }
+
+    /// Returns the address of the server: the control connection peer, or the address requested to the proxy
+    fn server_addr(&self) -> FtpResult<TargetAddr> {
+        match (&self.proxy_ctx, &self.transport) {
+            (Some(proxy_ctx), _) => Ok(proxy_ctx.server.clone()),
+            (None, Some(_)) => Ok(TargetAddr::ControlHost(0)),
+            (None, None) => Ok(TargetAddr::Ip(self.peer_addr()?)),
+        }
+    }
+
+    /// Returns the address of the server this stream is connected to
+    fn peer_addr(&self) -> FtpResult<SocketAddr> {
+        Ok(self.control_tcp_stream()?.peer_addr()?)
+    }
+
+    /// Returns the local address of the control connection
+    fn local_addr(&self) -> FtpResult<SocketAddr> {
+        Ok(self.control_tcp_stream()?.local_addr()?)
+    }
+
+    /// Returns the TcpStream of the control connection; fails on custom transports
+    fn control_tcp_stream(&self) -> FtpResult<&TcpStream> {
+        self.reader.get_ref().get_ref().ok_or_else(|| {
+            FtpError::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the control connection of a custom transport has no socket address",
+            ))
+        })
+    }
+
     async fn read_line<'s>(&mut self, line_buffer: &'s mut String) -> FtpResult<&'s str> {
 
         line_buffer.clear();
         
-        match self.reader.read_line(line_buffer).await {
+        match Self::with_timeout(self.control_timeout, self.reader.read_line(line_buffer)).await {
             Ok(size) => {
                 if size == 0 {
                     debug!("ERR read_line: EOF");
@@ -906,7 +2056,7 @@ impl FtpStream {
             },
             Err(e) => {
                 debug!("ERR read_line: {:?}", e);
-                return Err(e.into())
+                return Err(e)
             },
         };
     
@@ -919,16 +2069,172 @@ impl FtpStream {
     async fn stream_lines(&mut self, cmd: Command) -> FtpResult<Vec<String>> {
         let mut data_stream = BufReader::new(self.data_command(cmd).await?);
         self.read_response_in(&[Status::AboutToSend, Status::AlreadyOpen]).await?;
-        let lines = Self::get_lines_from_stream(&mut data_stream).await;
-        self.finalize_retr_stream(data_stream).await?;
+        let lines = Self::get_lines_from_stream(&mut data_stream, self.data_timeout).await;
+        self.finalize_retr_data_stream(data_stream.into_inner()).await?;
         lines
     }
 }
 
+/// Delay between two connection attempts to the addresses a host name resolves to (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Interval between two checks of the active mode listener, when an accept timeout is set
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Interval between two checks of the connection attempts raced by the sync `connect_addrs`
+#[cfg(feature = "sync")]
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Start a non-blocking connection towards `addr`
+#[cfg(feature = "sync")]
+fn start_connect(addr: SocketAddr) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => Ok(socket),
+        Err(err) if is_connect_in_progress(&err) => Ok(socket),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns whether a non-blocking connection has been established, or its error
+#[cfg(feature = "sync")]
+fn poll_connect(socket: &socket2::Socket) -> std::io::Result<bool> {
+    if let Some(err) = socket.take_error()? {
+        return Err(err);
+    }
+    match socket.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotConnected => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(all(feature = "sync", unix))]
+fn is_connect_in_progress(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EINPROGRESS)
+}
+
+#[cfg(all(feature = "sync", not(unix)))]
+fn is_connect_in_progress(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::WouldBlock
+}
+
+/// Returns the IPv4 address of IPv4-mapped IPv6 addresses, so that addresses of both families can be compared
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+/// Sort resolved addresses so that address families alternate, starting with the family of the first address (RFC 8305)
+fn interleave_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut preferred, mut others): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut sorted = Vec::with_capacity(preferred.len() + others.len());
+    preferred.reverse();
+    others.reverse();
+    loop {
+        match (preferred.pop(), others.pop()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Data stream which sends keep-alive NOOPs on the control connection while it's read
+#[cfg(feature = "sync")]
+struct KeepAliveReader<'a> {
+    stream: &'a mut DataStreamSync,
+    ftp: &'a mut FtpStreamSync,
+}
+
+#[cfg(feature = "sync")]
+impl std::io::Read for KeepAliveReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.ftp
+            .keep_alive()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Data stream which sends keep-alive NOOPs on the control connection while it's read.
+/// The NOOP is written as soon as the control connection is ready, without delaying the reads.
+#[cfg(feature = "async")]
+struct KeepAliveReaderAsync<'a> {
+    stream: &'a mut DataStreamAsync,
+    ftp: &'a mut FtpStreamAsync,
+    /// Bytes of the NOOP command not written yet
+    noop: Option<&'static [u8]>,
+}
+
+#[cfg(feature = "async")]
+impl KeepAliveReaderAsync<'_> {
+    /// Write the rest of the NOOP command being sent, if any
+    async fn finish(mut self) -> FtpResult<()> {
+        if let Some(noop) = self.noop {
+            FtpStreamAsync::with_timeout(self.ftp.control_timeout, self.ftp.reader.get_mut().write_all(noop)).await?;
+            self.noop_sent();
+        }
+        Ok(())
+    }
+
+    fn noop_sent(&mut self) {
+        self.noop = None;
+        self.ftp.internals.touch();
+        self.ftp.internals.pending_noops += 1;
+    }
+}
+
+#[cfg(feature = "async")]
+impl async_std::io::Read for KeepAliveReaderAsync<'_> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::task::Poll;
+
+        let this = &mut *self;
+        let noop_due = match this.ftp.keep_alive_config.noop_interval {
+            Some(interval) => this.noop.is_none() && this.ftp.internals.is_idle_for(interval),
+            None => false,
+        };
+        if noop_due {
+            debug!("Sending keep-alive NOOP during transfer");
+            this.noop = Some(b"NOOP\r\n");
+        }
+        while let Some(noop) = this.noop {
+            match async_std::io::Write::poll_write(std::pin::Pin::new(this.ftp.reader.get_mut()), cx, noop) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(size)) if size < noop.len() => this.noop = Some(&noop[size..]),
+                Poll::Ready(Ok(_)) => this.noop_sent(),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                // Retried on the next read
+                Poll::Pending => break,
+            }
+        }
+        async_std::io::Read::poll_read(std::pin::Pin::new(&mut *this.stream), cx, buf)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
+    use crate::mock::{Action, MockHttpProxy, MockServer, MockSocksProxy};
+    #[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+    use crate::mock::TlsAction;
     use crate::test::*;
     use crate::types::FormatControl;
 
@@ -940,13 +2246,16 @@ mod test {
     #[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
     use async_std::io::Cursor;
 
-    #[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
+    #[maybe_async_cfg::maybe(sync(feature="sync-secure"), async(feature="async-secure"))]
+    use async_native_tls::TlsConnector;
+
+    #[maybe_async_cfg::maybe(sync(feature="sync-secure"), async(feature="async-secure"))]
     #[maybe_async_cfg::only_if(async)]
     fn test_tls_connector() -> TlsConnector {
         TlsConnector::new()
     }
 
-    #[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
+    #[maybe_async_cfg::maybe(sync(feature="sync-secure"), async(feature="async-secure"))]
     #[maybe_async_cfg::only_if(sync)]
     fn test_tls_connector() -> TlsConnector {
         TlsConnector::new().unwrap()
@@ -971,7 +2280,7 @@ mod test {
             .ok()
             .unwrap();
         // Set timeout (to test ref to tls)
-        assert!(ftp_stream.get_ref().await.set_ttl(255).is_ok());
+        assert!(ftp_stream.get_ref().await.unwrap().set_ttl(255).is_ok());
         // Login
         assert!(ftp_stream.login(TEST_TLS_SERVER_LOGIN, TEST_TLS_SERVER_PASSWORD).await.is_ok());
         // PWD
@@ -980,6 +2289,139 @@ mod test {
         assert!(ftp_stream.quit().await.is_ok());
     }
 
+    #[maybe_async_cfg::maybe(sync(feature="sync-rustls", test), async(feature="async-rustls", async_attributes::test))]
+    async fn should_connect_with_implicit_tls() {
+        crate::log_init();
+        let (server_config, connector) = crate::mock::tls_configs();
+        let server = crate::mock::MockTlsServer::start(server_config, vec![
+            ("PBSZ 0", "200 PBSZ=0"),
+            ("PROT P", "200 Protection set to Private"),
+            ("QUIT", "221 Goodbye"),
+        ]);
+        let ftp_stream = FtpStream::connect_secure_implicit(server.addr().to_string().as_str(), connector, "localhost").await.unwrap();
+        assert!(ftp_stream.tls_ctx.is_some());
+        assert_eq!(ftp_stream.get_ref().await.unwrap().peer_addr().unwrap(), server.addr());
+        let tls_info = ftp_stream.tls_info().unwrap();
+        assert_eq!(tls_info.server_name.as_str(), "localhost");
+        assert!(tls_info.protocol_version.is_some());
+        assert!(tls_info.cipher_suite.is_some());
+        assert_eq!(tls_info.peer_certificates.len(), 1);
+        assert!(ftp_stream.data_tls_info().is_none());
+        assert!(ftp_stream.quit().await.is_ok());
+        assert_eq!(server.join(), vec!["PBSZ 0", "PROT P", "QUIT"]);
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync-rustls", test), async(feature="async-rustls", async_attributes::test))]
+    async fn should_report_data_connection_tls_info() {
+        crate::log_init();
+        let (server_config, connector) = crate::mock::tls_configs();
+        let server = crate::mock::MockTlsServer::start(server_config, vec![
+            ("PBSZ 0", TlsAction::Reply("200 PBSZ=0")),
+            ("PROT P", TlsAction::Reply("200 Protection set to Private")),
+            ("PASV", TlsAction::Pasv),
+            ("NLST", TlsAction::SendTls(b"a.txt\r\nb.txt\r\n")),
+            ("QUIT", TlsAction::Reply("221 Goodbye")),
+        ]);
+        let mut ftp_stream = FtpStream::connect_secure_implicit(server.addr().to_string().as_str(), connector, "localhost")
+            .await
+            .unwrap();
+        assert!(ftp_stream.data_tls_info().is_none());
+        assert_eq!(ftp_stream.nlst(None).await.unwrap(), vec!["a.txt", "b.txt"]);
+        let tls_info = ftp_stream.data_tls_info().unwrap();
+        assert_eq!(tls_info.server_name.as_str(), "localhost");
+        assert!(tls_info.protocol_version.is_some());
+        assert!(tls_info.cipher_suite.is_some());
+        assert_eq!(tls_info.peer_certificates.len(), 1);
+        assert!(ftp_stream.quit().await.is_ok());
+        assert_eq!(server.join(), vec!["PBSZ 0", "PROT P", "PASV", "NLST", "QUIT"]);
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync-rustls", test), async(feature="async-rustls", async_attributes::test))]
+    async fn should_exchange_close_notify_on_clear_command_channel() {
+        crate::log_init();
+        let (server_config, connector) = crate::mock::tls_configs();
+        let server = crate::mock::MockTlsServer::start(server_config, vec![
+            ("PBSZ 0", TlsAction::Reply("200 PBSZ=0")),
+            ("PROT P", TlsAction::Reply("200 Protection set to Private")),
+            ("CCC", TlsAction::Ccc),
+            ("NOOP", TlsAction::Reply("200 NOOP ok")),
+            ("QUIT", TlsAction::Reply("221 Goodbye")),
+        ]);
+        let mut ftp_stream = FtpStream::connect_secure_implicit(server.addr().to_string().as_str(), connector, "localhost")
+            .await
+            .unwrap();
+        assert!(ftp_stream.set_control_timeout(Some(Duration::from_secs(5))).is_ok());
+        let mut ftp_stream = ftp_stream.clear_command_channel().await.unwrap();
+        assert!(ftp_stream.tls_info().is_none());
+        // The close_notify of the server has been consumed, so the next reply is read in clear
+        assert!(ftp_stream.noop().await.is_ok());
+        assert!(ftp_stream.quit().await.is_ok());
+        assert_eq!(server.join(), vec!["PBSZ 0", "PROT P", "CCC", "NOOP", "QUIT"]);
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync-rustls", test), async(feature="async-rustls", async_attributes::test))]
+    async fn should_not_hang_on_clear_command_channel_without_server_close_notify() {
+        crate::log_init();
+        let (server_config, connector) = crate::mock::tls_configs();
+        let server = crate::mock::MockTlsServer::start(server_config, vec![
+            ("PBSZ 0", TlsAction::Reply("200 PBSZ=0")),
+            ("PROT P", TlsAction::Reply("200 Protection set to Private")),
+            ("CCC", TlsAction::CccWithoutCloseNotify),
+            ("NOOP", TlsAction::Reply("200 NOOP ok")),
+            ("QUIT", TlsAction::Reply("221 Goodbye")),
+        ]);
+        let ftp_stream = FtpStream::connect_secure_implicit(server.addr().to_string().as_str(), connector, "localhost")
+            .await
+            .unwrap();
+        // No control timeout: the wait for close_notify is bounded on its own
+        let started = Instant::now();
+        let mut ftp_stream = ftp_stream.clear_command_channel().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(ftp_stream.noop().await.is_ok());
+        assert!(ftp_stream.quit().await.is_ok());
+        assert_eq!(server.join(), vec!["PBSZ 0", "PROT P", "CCC", "NOOP", "QUIT"]);
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync-rustls", test), async(feature="async-rustls", async_attributes::test))]
+    async fn should_switch_data_protection_level() {
+        crate::log_init();
+        let (server_config, connector) = crate::mock::tls_configs();
+        let server = crate::mock::MockTlsServer::start(server_config, vec![
+            ("PBSZ 0", "200 PBSZ=0"),
+            ("PROT P", "200 Protection set to Private"),
+            ("PBSZ 0", "200 PBSZ=0"),
+            ("PROT C", "200 Protection set to Clear"),
+            ("PBSZ 0", "200 PBSZ=0"),
+            ("PROT P", "536 Requested PROT level not supported by mechanism"),
+            ("QUIT", "221 Goodbye"),
+        ]);
+        let mut ftp_stream = FtpStream::connect_secure_implicit(server.addr().to_string().as_str(), connector, "localhost")
+            .await
+            .unwrap();
+        assert_eq!(ftp_stream.protection_level(), Some(ProtectionLevel::Private));
+        assert!(ftp_stream.set_protection_level(ProtectionLevel::Clear).await.is_ok());
+        assert_eq!(ftp_stream.protection_level(), Some(ProtectionLevel::Clear));
+        // The previous level is kept if the server refuses the new one
+        assert!(ftp_stream.set_protection_level(ProtectionLevel::Private).await.is_err());
+        assert_eq!(ftp_stream.protection_level(), Some(ProtectionLevel::Clear));
+        assert!(ftp_stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="_sync-tls", test), async(feature="_async-tls", async_attributes::test))]
+    async fn should_not_set_protection_level_on_clear_session() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![("QUIT", Action::Reply("221 Goodbye"))]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        assert_eq!(stream.protection_level(), None);
+        assert!(matches!(
+            stream.set_protection_level(ProtectionLevel::Clear).await,
+            Err(FtpError::SecureError(_))
+        ));
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
     #[maybe_async_cfg::maybe(sync(feature="sync-secure", test), async(feature="async-secure", async_attributes::test))]
     #[serial]
     async fn should_work_after_clear_command_channel() {
@@ -1034,7 +2476,7 @@ mod test {
     async fn get_ref() {
         crate::log_init();
         let stream: FtpStream = test_setup_stream().await;
-        assert!(stream.get_ref().await.set_ttl(255).is_ok());
+        assert!(stream.get_ref().await.unwrap().set_ttl(255).is_ok());
         test_finalize_stream(stream).await;
     }
 
@@ -1259,6 +2701,506 @@ mod test {
         test_finalize_stream(stream).await;
     }
 
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_use_extended_passive_mode() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("EPSV", Action::Epsv),
+            ("NLST", Action::Send(b"a.txt\r\nb.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_mode(Mode::ExtendedPassive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt", "b.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_fall_back_to_pasv_once_if_epsv_is_not_implemented() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("EPSV", Action::Reply("502 Command not implemented")),
+            ("PASV", Action::Pasv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            // EPSV must not be sent again
+            ("PASV", Action::Pasv),
+            ("NLST", Action::Send(b"b.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_mode(Mode::ExtendedPassive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["b.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_not_fall_back_to_pasv_after_epsv_all() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("EPSV ALL", Action::Reply("200 EPSV ALL ok")),
+            ("EPSV", Action::Reply("502 Command not implemented")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        assert!(stream.epsv_all().await.is_ok());
+        assert_eq!(stream.mode, Mode::ExtendedPassive);
+        assert!(stream.nlst(None).await.is_err());
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_use_extended_active_mode() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("EPRT |1|127.0.0.1|", Action::Eprt),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_mode(Mode::ExtendedActive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_use_active_mode() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PORT 127,0,0,1,", Action::Port),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_mode(Mode::Active);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_use_extended_modes_over_ipv6() {
+        crate::log_init();
+        let server = MockServer::start("[::1]:0", vec![
+            ("EPSV", Action::Epsv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("EPRT |2|::1|", Action::Eprt),
+            ("NLST", Action::Send(b"b.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let addr = server.addr().to_string();
+        assert!(addr.starts_with("[::1]:"));
+        let mut stream = FtpStream::connect(addr.as_str()).await.unwrap();
+        // PASV would be used on IPv4
+        stream.set_mode(Mode::Passive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        // PORT would be used on IPv4
+        stream.set_mode(Mode::Active);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["b.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_replace_private_passive_address() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PASV", Action::PasvAs([192, 168, 1, 1])),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_passive_address_policy(PassiveAddressPolicy::UsePeerAddressIfPrivate);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_refuse_passive_address_of_other_host() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PASV", Action::PasvAs([10, 0, 0, 1])),
+            ("PASV", Action::PasvAs([127, 0, 0, 1])),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        assert!(matches!(
+            stream.nlst(None).await.err().unwrap(),
+            FtpError::UnsafeDataAddress(addr) if addr.ip() == IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+        ));
+        stream.set_passive_target_policy(PassiveTargetPolicy::allow_all());
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_walk_active_mode_port_range() {
+        crate::log_init();
+        // Keep the first port of the range busy
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let first_port = busy.local_addr().unwrap().port();
+        let last_port = first_port.saturating_add(16);
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PORT 127,0,0,1,", Action::Port),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str())
+            .await
+            .unwrap()
+            .active_mode_with_config(ActiveModeConfig {
+                bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                port_range: Some(first_port..=last_port),
+                ..ActiveModeConfig::default()
+            });
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        // Get port from PORT command
+        let commands = server.join();
+        let args: Vec<u16> = commands[0][5..].split(',').map(|x| x.parse().unwrap()).collect();
+        let port = args[4] * 256 + args[5];
+        assert!(port > first_port && port <= last_port);
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_time_out_waiting_for_active_data_connection() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PORT 127,0,0,1,", Action::Port),
+            ("NLST", Action::Reply("150 Opening data connection")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str())
+            .await
+            .unwrap()
+            .active_mode_with_config(ActiveModeConfig {
+                bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                accept_timeout: Some(Duration::from_millis(200)),
+                ..ActiveModeConfig::default()
+            });
+        assert!(matches!(stream.nlst(None).await.err().unwrap(), FtpError::Timeout));
+        drop(stream);
+        server.join();
+    }
+
+    // Binding to 127.0.0.2 requires the whole loopback network to be available
+    #[cfg(target_os = "linux")]
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_reject_data_connections_from_other_hosts() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PORT 127,0,0,1,", Action::Port),
+            ("NLST", Action::SendAfterIntruder(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str())
+            .await
+            .unwrap()
+            .active_mode_with_config(ActiveModeConfig {
+                bind_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                accept_timeout: Some(Duration::from_secs(5)),
+                ..ActiveModeConfig::default()
+            });
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_time_out_waiting_for_reply() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("NOOP", Action::Ignore),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect_timeout(server.addr().to_string().as_str(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(stream.set_control_timeout(Some(Duration::from_millis(200))).is_ok());
+        assert!(matches!(stream.noop().await.err().unwrap(), FtpError::Timeout));
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[test]
+    fn should_interleave_address_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:21".parse().unwrap(),
+            "[::2]:21".parse().unwrap(),
+            "[::3]:21".parse().unwrap(),
+            "10.0.0.1:21".parse().unwrap(),
+        ];
+        let sorted: Vec<String> = interleave_addrs(addrs).iter().map(|x| x.to_string()).collect();
+        assert_eq!(sorted, vec!["[::1]:21", "10.0.0.1:21", "[::2]:21", "[::3]:21"]);
+        assert!(interleave_addrs(vec![]).is_empty());
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_connect_to_first_reachable_address() {
+        crate::log_init();
+        // Ports which refuse connections
+        let closed: Vec<SocketAddr> = (0..2)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap())
+            .collect();
+        let server = MockServer::start("127.0.0.1:0", vec![]);
+        let stream = FtpStream::connect_addrs(vec![closed[0], server.addr(), closed[1]], Some(Duration::from_secs(5)))
+            .await;
+        assert!(stream.is_ok());
+        drop(stream);
+        server.join();
+        match FtpStream::connect_addrs(closed.clone(), Some(Duration::from_secs(5))).await.err().unwrap() {
+            FtpError::ConnectFailed(errors) => {
+                assert_eq!(errors.iter().map(|(addr, _)| *addr).collect::<Vec<SocketAddr>>(), closed);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_send_keep_alive_noops() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("NOOP", Action::Reply("200 NOOP ok")),
+            ("PASV", Action::Pasv),
+            ("STOR test.txt", Action::Receive),
+            ("NOOP", Action::Reply("200 NOOP ok")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        assert!(stream.keep_alive().await.is_ok());
+        assert!(stream
+            .set_keep_alive(KeepAliveConfig {
+                noop_interval: Some(Duration::ZERO),
+                tcp_keepalive: Some(Duration::from_secs(60)),
+            })
+            .is_ok());
+        stream.set_mode(Mode::Passive);
+        // NOOP sent while idle
+        assert!(stream.keep_alive().await.is_ok());
+        // NOOP sent during the transfer, replied after the 226
+        let mut reader = Cursor::new("hello, world!".as_bytes());
+        assert_eq!(stream.put_file("test.txt", &mut reader).await.unwrap(), 13);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[cfg(feature = "async")]
+    #[async_attributes::test]
+    async fn should_send_keep_alive_noops_while_retr_is_read() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PASV", Action::Pasv),
+            ("RETR test.txt", Action::Send(b"hello")),
+            ("NOOP", Action::Reply("200 NOOP ok")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStreamAsync::connect(server.addr().to_string().as_str()).await.unwrap();
+        assert!(stream
+            .set_keep_alive(KeepAliveConfig { noop_interval: Some(Duration::ZERO), tcp_keepalive: None })
+            .is_ok());
+        stream.set_mode(Mode::Passive);
+        // A single read once the data has arrived, so a single NOOP
+        let data = stream
+            .retr("test.txt", |reader| {
+                let mut buffer = vec![0; 5];
+                async_std::task::block_on(async {
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                    reader.read_exact(&mut buffer).await
+                })
+                .map_err(FtpError::ConnectionError)?;
+                Ok(buffer)
+            })
+            .await
+            .unwrap();
+        assert_eq!(data.as_slice(), b"hello");
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_tunnel_connections_through_socks5_proxy() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PASV", Action::Pasv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("PORT 127,0,0,1,", Action::Port),
+            ("NLST", Action::Send(b"b.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let server_addr = server.addr();
+        let proxy = MockSocksProxy::start(3, Some(("proxy", "secret")));
+        let mut stream = FtpStream::connect_with_proxy(
+            server_addr.to_string().as_str(),
+            Proxy::Socks5 {
+                addr: proxy.addr().to_string(),
+                credentials: Some(ProxyCredentials::new("proxy", String::from("secret"))),
+            },
+        ).await.unwrap();
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        stream.set_mode(Mode::Active);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["b.txt"]);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+
+        let requests = proxy.join();
+        assert_eq!(requests[0], format!("CONNECT {}", server_addr));
+        assert!(requests[1].starts_with("CONNECT 127.0.0.1:"));
+        assert_eq!(requests[2], format!("BIND {}", server_addr));
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_tunnel_connections_through_http_proxy() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("PASV", Action::Pasv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let server_addr = server.addr();
+        // "proxy:secret"
+        let proxy = MockHttpProxy::start(2, Some("cHJveHk6c2VjcmV0"));
+        let mut stream = FtpStream::connect_with_proxy(
+            server_addr.to_string().as_str(),
+            Proxy::Http {
+                addr: proxy.addr().to_string(),
+                credentials: Some(ProxyCredentials::new("proxy", "secret")),
+            },
+        ).await.unwrap();
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        // The proxy can't accept connections for the client
+        stream.set_mode(Mode::Active);
+        assert!(matches!(stream.nlst(None).await.err().unwrap(), FtpError::ProxyError(_)));
+        assert!(stream.quit().await.is_ok());
+        server.join();
+
+        let requests = proxy.join();
+        assert_eq!(requests[0], format!("CONNECT {} HTTP/1.1", server_addr));
+        assert!(requests[1].starts_with("CONNECT 127.0.0.1:"));
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_report_http_proxy_status_line() {
+        crate::log_init();
+        let proxy = MockHttpProxy::start(1, Some("cHJveHk6c2VjcmV0"));
+        let err = FtpStream::connect_with_proxy(
+            "127.0.0.1:21",
+            Proxy::Http {
+                addr: proxy.addr().to_string(),
+                credentials: None,
+            },
+        ).await.err().unwrap();
+        match err {
+            FtpError::ProxyError(status_line) => {
+                assert_eq!(status_line.as_str(), "HTTP/1.1 407 Proxy Authentication Required")
+            },
+            err => panic!("unexpected error: {}", err),
+        }
+        proxy.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_login_through_ftp_proxy_with_site() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("USER fwuser", Action::Reply("331 Password required")),
+            ("PASS fwpass", Action::Reply("230 Logged in to proxy")),
+            ("SITE ftp.example.com", Action::Reply("220 Connected to ftp.example.com")),
+            ("USER test", Action::Reply("331 Password required")),
+            ("PASS secret", Action::Reply("230 Logged in")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_ftp_proxy(Some(FtpProxy {
+            kind: FtpProxyKind::Site,
+            host: "ftp.example.com".to_string(),
+            credentials: Some(ProxyCredentials::new("fwuser", "fwpass")),
+        }));
+        assert!(stream.login("test", "secret").await.is_ok());
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_login_through_ftp_proxy_with_acct() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("USER test@ftp.example.com fwuser", Action::Reply("331 Password required")),
+            ("PASS secret", Action::Reply("332 Need account")),
+            ("ACCT fwpass", Action::Reply("230 Logged in")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let mut stream = FtpStream::connect(server.addr().to_string().as_str()).await.unwrap();
+        stream.set_ftp_proxy(Some(FtpProxy {
+            kind: FtpProxyKind::UserAtHostProxyUserAcct,
+            host: "ftp.example.com".to_string(),
+            credentials: Some(ProxyCredentials::new("fwuser", "fwpass")),
+        }));
+        assert!(stream.login("test", "secret").await.is_ok());
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_run_over_custom_transport() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("EPSV", Action::Epsv),
+            ("NLST", Action::Send(b"a.txt\r\n")),
+            ("PASV", Action::Pasv),
+            ("NLST", Action::Send(b"b.txt\r\n")),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]);
+        let transport = Arc::new(TestTransport { addr: server.addr() });
+        let mut stream = FtpStream::connect_with_transport(transport).await.unwrap();
+        assert!(stream.get_ref().await.is_err());
+        stream.set_mode(Mode::ExtendedPassive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["a.txt"]);
+        stream.set_mode(Mode::Passive);
+        assert_eq!(stream.nlst(None).await.unwrap(), vec!["b.txt"]);
+        // The test transport doesn't support active mode
+        stream.set_mode(Mode::Active);
+        assert!(stream.nlst(None).await.is_err());
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    /// Transport which opens TCP connections, towards the mock server for EPSV
+    #[derive(Debug)]
+    struct TestTransport {
+        addr: std::net::SocketAddr,
+    }
+
+    #[cfg(feature = "sync")]
+    impl TransportSync for TestTransport {
+        fn connect_control(&self) -> std::io::Result<Box<dyn TransportStreamSync>> {
+            Ok(Box::new(std::net::TcpStream::connect(self.addr)?))
+        }
+
+        fn connect_data(&self, ip: Option<IpAddr>, port: u16) -> std::io::Result<Box<dyn TransportStreamSync>> {
+            let ip = ip.unwrap_or_else(|| self.addr.ip());
+            Ok(Box::new(std::net::TcpStream::connect((ip, port))?))
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[async_trait::async_trait]
+    impl TransportAsync for TestTransport {
+        async fn connect_control(&self) -> std::io::Result<Box<dyn TransportStreamAsync>> {
+            Ok(Box::new(async_std::net::TcpStream::connect(self.addr).await?))
+        }
+
+        async fn connect_data(&self, ip: Option<IpAddr>, port: u16) -> std::io::Result<Box<dyn TransportStreamAsync>> {
+            let ip = ip.unwrap_or_else(|| self.addr.ip());
+            Ok(Box::new(async_std::net::TcpStream::connect((ip, port)).await?))
+        }
+    }
+
     // -- test utils
 
     #[maybe_async_cfg::maybe(sync(feature="sync"), async(feature="async"))]
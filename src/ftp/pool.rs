@@ -0,0 +1,338 @@
+//! # Pool
+//!
+//! This module exposes a pool of FTP sessions towards the same server
+
+maybe_async_cfg::content! {
+
+#![maybe_async_cfg::default(
+    idents(
+        FtpStream,
+        FtpStreamBuilder,
+        FtpPool,
+        PooledFtpStream,
+        PoolEntry,
+        PoolSlots,
+    ),
+)]
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use super::{FtpStream, FtpStreamBuilder};
+use crate::types::FtpResult;
+
+use std::ops::{Deref, DerefMut};
+
+/// Session kept by the pool, with the working directory it must be brought back to
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+struct PoolEntry {
+    stream: FtpStream,
+    root: String,
+}
+
+/// Slots of the pool: each slot is either an idle session or the permission to open a new one
+#[cfg(feature = "sync")]
+struct PoolSlotsSync {
+    sender: std::sync::mpsc::SyncSender<Option<PoolEntrySync>>,
+    receiver: std::sync::Mutex<std::sync::mpsc::Receiver<Option<PoolEntrySync>>>,
+}
+
+#[cfg(feature = "sync")]
+impl PoolSlotsSync {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(size);
+        for _ in 0..size {
+            sender.send(None).expect("pool receiver is alive");
+        }
+        Self { sender, receiver: std::sync::Mutex::new(receiver) }
+    }
+
+    /// Wait for a free slot
+    fn take(&self) -> Option<PoolEntrySync> {
+        let receiver = self.receiver.lock().unwrap_or_else(|err| err.into_inner());
+        receiver.recv().expect("pool sender is alive")
+    }
+
+    /// Give a slot back; never blocks, since there are never more slots than the channel capacity
+    fn put(&self, slot: Option<PoolEntrySync>) {
+        let _ = self.sender.try_send(slot);
+    }
+}
+
+/// Slots of the pool: each slot is either an idle session or the permission to open a new one
+#[cfg(feature = "async")]
+struct PoolSlotsAsync {
+    sender: async_std::channel::Sender<Option<PoolEntryAsync>>,
+    receiver: async_std::channel::Receiver<Option<PoolEntryAsync>>,
+}
+
+#[cfg(feature = "async")]
+impl PoolSlotsAsync {
+    fn new(size: usize) -> Self {
+        let (sender, receiver) = async_std::channel::bounded(size);
+        for _ in 0..size {
+            sender.try_send(None).expect("pool receiver is alive");
+        }
+        Self { sender, receiver }
+    }
+
+    /// Wait for a free slot
+    async fn take(&self) -> Option<PoolEntryAsync> {
+        self.receiver.recv().await.expect("pool sender is alive")
+    }
+
+    /// Give a slot back; never blocks, since there are never more slots than the channel capacity
+    fn put(&self, slot: Option<PoolEntryAsync>) {
+        let _ = self.sender.try_send(slot);
+    }
+}
+
+/// Pool of up to `max_size` logged-in sessions set up by the same `FtpStreamBuilder`.
+///
+/// Sessions are opened lazily and handed out as `PooledFtpStream` guards; when all the sessions are in use,
+/// `get()` waits for one to be returned. Idle sessions are checked with `NOOP` before being handed out,
+/// and replaced if broken. The working directory of a session is brought back to the one it had once set up
+/// when the session is returned to the pool (see `PooledFtpStream`).
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub struct FtpPool {
+    builder: FtpStreamBuilder,
+    slots: PoolSlots,
+    max_size: usize,
+}
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+impl FtpPool {
+    /// Create a pool of up to `max_size` sessions set up by `builder`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_size` is 0
+    pub fn new(builder: FtpStreamBuilder, max_size: usize) -> Self {
+        assert!(max_size > 0, "pool size must be greater than 0");
+        Self {
+            builder,
+            slots: PoolSlots::new(max_size),
+            max_size,
+        }
+    }
+
+    /// Maximum number of sessions opened by the pool
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Check out a session, waiting for one to be available if all of them are in use
+    pub async fn get(&self) -> FtpResult<PooledFtpStream<'_>> {
+        let entry = match self.slots.take().await {
+            Some(entry) => Self::check(entry).await,
+            None => None,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => match self.open().await {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.slots.put(None);
+                    return Err(err);
+                }
+            },
+        };
+        Ok(PooledFtpStream { pool: self, entry: Some(entry) })
+    }
+
+    /// Open and set up a new session
+    async fn open(&self) -> FtpResult<PoolEntry> {
+        debug!("Opening a new pooled session");
+        let mut stream = self.builder.connect().await?;
+        let root = stream.pwd().await?;
+        Ok(PoolEntry { stream, root })
+    }
+
+    /// Make sure an idle session is still alive
+    async fn check(mut entry: PoolEntry) -> Option<PoolEntry> {
+        match entry.stream.noop().await {
+            Ok(()) => Some(entry),
+            Err(err) => {
+                debug!("Discarding pooled session: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Session checked out from a `FtpPool`; it's returned to the pool with `release()`, or when dropped.
+///
+/// When the sync guard is dropped, the working directory is restored before the session is returned;
+/// the session is discarded if it can't be restored, or if the guard is dropped in the middle of a transfer.
+/// The async guard can't send commands when dropped, so its session is discarded: call `release()` to keep it.
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub struct PooledFtpStream<'a> {
+    pool: &'a FtpPool,
+    entry: Option<PoolEntry>,
+}
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+impl PooledFtpStream<'_> {
+    /// Restore the working directory and return the session to the pool.
+    /// If the working directory can't be restored, the session is discarded and the error is returned.
+    pub async fn release(mut self) -> FtpResult<()> {
+        let mut entry = self.entry.take().expect("pooled session is present");
+        match entry.stream.cwd(entry.root.as_str()).await {
+            Ok(()) => {
+                self.pool.slots.put(Some(entry));
+                Ok(())
+            }
+            Err(err) => {
+                self.pool.slots.put(None);
+                Err(err)
+            }
+        }
+    }
+
+    /// Close the session instead of returning it to the pool (e.g. after an unrecoverable error)
+    pub fn discard(mut self) {
+        self.entry = None;
+        self.pool.slots.put(None);
+    }
+}
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+impl Deref for PooledFtpStream<'_> {
+    type Target = FtpStream;
+
+    fn deref(&self) -> &FtpStream {
+        &self.entry.as_ref().expect("pooled session is present").stream
+    }
+}
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+impl DerefMut for PooledFtpStream<'_> {
+    fn deref_mut(&mut self) -> &mut FtpStream {
+        &mut self.entry.as_mut().expect("pooled session is present").stream
+    }
+}
+
+#[cfg(feature = "sync")]
+impl Drop for PooledFtpStreamSync<'_> {
+    fn drop(&mut self) {
+        let slot = match self.entry.take() {
+            // Already released or discarded
+            None => return,
+            Some(entry) if entry.stream.internals.transfer_in_progress => {
+                debug!("Discarding pooled session dropped during a transfer");
+                None
+            }
+            Some(mut entry) => match entry.stream.cwd(entry.root.as_str()) {
+                Ok(()) => Some(entry),
+                Err(err) => {
+                    debug!("Discarding pooled session: {}", err);
+                    None
+                }
+            },
+        };
+        self.pool.slots.put(slot);
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for PooledFtpStreamAsync<'_> {
+    fn drop(&mut self) {
+        if self.entry.take().is_some() {
+            debug!("Discarding pooled session dropped without release()");
+            self.pool.slots.put(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::mock::{Action, MockServer};
+
+    use pretty_assertions::assert_eq;
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_reuse_pooled_sessions() {
+        crate::log_init();
+        let server = MockServer::start_sessions("127.0.0.1:0", vec![
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+                ("CWD sub", Action::Reply("250 Directory changed")),
+                ("CWD /home", Action::Reply("250 Directory changed")),
+                ("NOOP", Action::Reply("421 Service not available, closing control connection")),
+            ],
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+                ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+                ("CWD /home", Action::Reply("250 Directory changed")),
+            ],
+        ]);
+        let pool = FtpPool::new(FtpStreamBuilder::new(server.addr().to_string()).login("test", "secret"), 1);
+        assert_eq!(pool.max_size(), 1);
+        let mut stream = pool.get().await.unwrap();
+        assert!(stream.cwd("sub").await.is_ok());
+        assert!(stream.release().await.is_ok());
+        // The idle session is broken and gets replaced
+        let mut stream = pool.get().await.unwrap();
+        assert_eq!(stream.pwd().await.unwrap().as_str(), "/home");
+        assert!(stream.release().await.is_ok());
+        drop(pool);
+        server.join();
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn should_restore_working_directory_when_dropped() {
+        crate::log_init();
+        let server = MockServer::start("127.0.0.1:0", vec![
+            ("USER test", Action::Reply("331 Password required")),
+            ("PASS secret", Action::Reply("230 Logged in")),
+            ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+            ("CWD sub", Action::Reply("250 Directory changed")),
+            ("CWD /home", Action::Reply("250 Directory changed")),
+        ]);
+        let pool = FtpPoolSync::new(FtpStreamBuilderSync::new(server.addr().to_string()).login("test", "secret"), 1);
+        let mut stream = pool.get().unwrap();
+        assert!(stream.cwd("sub").is_ok());
+        drop(stream);
+        // The directory has been restored on drop, without checking the session out again
+        drop(pool);
+        assert_eq!(server.join(), vec!["USER test", "PASS secret", "PWD", "CWD sub", "CWD /home"]);
+    }
+
+    #[cfg(feature = "async")]
+    #[async_attributes::test]
+    async fn should_discard_dropped_session() {
+        crate::log_init();
+        let server = MockServer::start_sessions("127.0.0.1:0", vec![
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+                ("CWD sub", Action::Reply("250 Directory changed")),
+            ],
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("PWD", Action::Reply("257 \"/home\" is the current directory")),
+            ],
+        ]);
+        let pool = FtpPoolAsync::new(FtpStreamBuilderAsync::new(server.addr().to_string()).login("test", "secret"), 1);
+        let mut stream = pool.get().await.unwrap();
+        assert!(stream.cwd("sub").await.is_ok());
+        drop(stream);
+        // A new session is opened in place of the dropped one
+        let stream = pool.get().await.unwrap();
+        assert!(stream.release().await.is_ok());
+        drop(pool);
+        assert_eq!(
+            server.join(),
+            vec!["USER test", "PASS secret", "PWD", "CWD sub", "USER test", "PASS secret", "PWD"]
+        );
+    }
+}
+
+}
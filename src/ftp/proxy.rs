@@ -0,0 +1,434 @@
+//! # Proxy
+//!
+//! This module exposes the client side of the proxy protocols used to tunnel control and data connections
+
+maybe_async_cfg::content! {
+
+#![maybe_async_cfg::default(
+    idents(
+        async_std(sync="std", async),
+        TcpStream(use),
+        socks5_connect(fn),
+        socks5_bind(fn),
+        socks5_accept(fn),
+        socks5_handshake(fn),
+        socks5_request(fn),
+        socks5_read_reply(fn),
+        http_connect(fn),
+    ),
+)]
+
+use crate::types::{FtpError, FtpResult, Proxy, ProxyCredentials};
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use async_std::net::TcpStream;
+
+#[cfg(feature = "sync")]
+use std::io::{Read, Write};
+
+#[cfg(feature = "async")]
+use async_std::prelude::*;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const SOCKS5_DEFAULT_PORT: u16 = 1080;
+const HTTP_PROXY_DEFAULT_PORT: u16 = 8080;
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS5_PASSWORD_AUTH_VERSION: u8 = 0x01;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_CMD_BIND: u8 = 0x02;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+const HTTP_MAX_RESPONSE_SIZE: usize = 8192;
+
+/// Address of a connection target, as requested to the proxy.
+/// Host names are resolved by the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+    /// Port on the host of the control connection, whose address is unknown (custom transports)
+    ControlHost(u16),
+}
+
+impl TargetAddr {
+    /// Parse `host[:port]`, `ip[:port]` or `[ipv6][:port]`; `default_port` is used if the port is missing
+    pub fn parse(addr: &str, default_port: u16) -> FtpResult<Self> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(Self::Ip(addr));
+        }
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            return Ok(Self::Ip(SocketAddr::new(ip, default_port)));
+        }
+        if let Some(ip) = addr.strip_prefix('[').and_then(|addr| addr.strip_suffix(']')) {
+            return Ok(Self::Ip(SocketAddr::new(ip.parse()?, default_port)));
+        }
+
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| Self::invalid(addr))?),
+            None => (addr, default_port),
+        };
+        if host.is_empty() || host.len() > 255 {
+            return Err(Self::invalid(addr));
+        }
+        Ok(match host.parse::<IpAddr>() {
+            Ok(ip) => Self::Ip(SocketAddr::new(ip, port)),
+            Err(_) => Self::Domain(host.to_string(), port),
+        })
+    }
+
+    /// Returns the same host with another port
+    pub fn with_port(&self, port: u16) -> Self {
+        match self {
+            Self::Ip(addr) => Self::Ip(SocketAddr::new(addr.ip(), port)),
+            Self::Domain(host, _) => Self::Domain(host.clone(), port),
+            Self::ControlHost(_) => Self::ControlHost(port),
+        }
+    }
+
+    /// Returns the port
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Domain(_, port) | Self::ControlHost(port) => *port,
+        }
+    }
+
+    /// Returns the IP address, if known
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ip(addr) => Some(addr.ip()),
+            Self::Domain(..) | Self::ControlHost(_) => None,
+        }
+    }
+
+    fn invalid(addr: &str) -> FtpError {
+        FtpError::ConnectionError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid address '{}'", addr),
+        ))
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Domain(host, port) => write!(f, "{}:{}", host, port),
+            Self::ControlHost(port) => write!(f, "<control host>:{}", port),
+        }
+    }
+}
+
+/// Proxy in use by a session, with the address of the server as requested to the proxy
+#[derive(Debug, Clone)]
+pub struct ProxyCtx {
+    pub proxy: Proxy,
+    pub server: TargetAddr,
+}
+
+impl ProxyCtx {
+    /// Address of the proxy (`host:port`)
+    pub fn addr(&self) -> &str {
+        match &self.proxy {
+            Proxy::Socks5 { addr, .. } | Proxy::Http { addr, .. } => addr.as_str(),
+        }
+    }
+
+    /// Port of the proxy used if its address has none
+    pub fn default_port(&self) -> u16 {
+        match &self.proxy {
+            Proxy::Socks5 { .. } => SOCKS5_DEFAULT_PORT,
+            Proxy::Http { .. } => HTTP_PROXY_DEFAULT_PORT,
+        }
+    }
+}
+
+/// Ask the SOCKS5 proxy connected with `stream` to connect to `target`
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub async fn socks5_connect(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>, target: &TargetAddr) -> FtpResult<()> {
+    socks5_handshake(stream, credentials).await?;
+    socks5_request(stream, SOCKS5_CMD_CONNECT, target).await?;
+    let bound = socks5_read_reply(stream).await?;
+    debug!("SOCKS5 proxy connected to {} (bound address {})", target, bound);
+    Ok(())
+}
+
+/// Ask the SOCKS5 proxy connected with `stream` to accept a connection from `target`.
+/// Returns the address the proxy is listening on, which must be advertised to the server.
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub async fn socks5_bind(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>, target: &TargetAddr) -> FtpResult<SocketAddr> {
+    socks5_handshake(stream, credentials).await?;
+    socks5_request(stream, SOCKS5_CMD_BIND, target).await?;
+    let addr = match socks5_read_reply(stream).await? {
+        // Some proxies don't report the address they're listening on
+        TargetAddr::Ip(addr) if addr.ip().is_unspecified() => SocketAddr::new(stream.peer_addr()?.ip(), addr.port()),
+        TargetAddr::Ip(addr) => addr,
+        addr => {
+            return Err(FtpError::ProxyError(format!("BIND address {} is not an IP address", addr)));
+        }
+    };
+    debug!("SOCKS5 proxy listening on {}", addr);
+    Ok(addr)
+}
+
+/// Wait for the SOCKS5 proxy to accept the connection requested with `socks5_bind`.
+/// Returns the address of the peer which connected to the proxy.
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub async fn socks5_accept(stream: &mut TcpStream) -> FtpResult<TargetAddr> {
+    let peer = socks5_read_reply(stream).await?;
+    debug!("SOCKS5 proxy accepted connection from {}", peer);
+    Ok(peer)
+}
+
+/// Negotiate the authentication method and authenticate
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+async fn socks5_handshake(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>) -> FtpResult<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD],
+        None => &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE],
+    };
+    stream.write_all(greeting).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(FtpError::ProxyError(format!("unsupported SOCKS version {}", reply[0])));
+    }
+
+    match (reply[1], credentials) {
+        (SOCKS5_AUTH_NONE, _) => Ok(()),
+        (SOCKS5_AUTH_PASSWORD, Some(credentials)) => {
+            trace!("SOCKS5 authentication as '{}'", credentials.username);
+            let (username, password) = (credentials.username.as_bytes(), credentials.password.as_bytes());
+            if username.len() > 255 || password.len() > 255 {
+                return Err(FtpError::ProxyError("username and password must be at most 255 bytes long".to_string()));
+            }
+            let mut request = vec![SOCKS5_PASSWORD_AUTH_VERSION, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await?;
+
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(FtpError::ProxyError("authentication failed".to_string()));
+            }
+            Ok(())
+        },
+        (SOCKS5_AUTH_UNACCEPTABLE, _) => Err(FtpError::ProxyError("no acceptable authentication method".to_string())),
+        (method, _) => Err(FtpError::ProxyError(format!("unexpected authentication method {}", method))),
+    }
+}
+
+/// Send a request with `command` for `target`
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+async fn socks5_request(stream: &mut TcpStream, command: u8, target: &TargetAddr) -> FtpResult<()> {
+    let mut request = vec![SOCKS5_VERSION, command, 0x00];
+    let port = match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        },
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        },
+        TargetAddr::Domain(host, port) => {
+            request.push(SOCKS5_ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            *port
+        },
+        TargetAddr::ControlHost(_) => {
+            return Err(FtpError::ProxyError(format!("cannot request {} to the proxy", target)));
+        },
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    Ok(())
+}
+
+/// Read a reply and return the address it carries
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+async fn socks5_read_reply(stream: &mut TcpStream) -> FtpResult<TargetAddr> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(FtpError::ProxyError(format!("unsupported SOCKS version {}", header[0])));
+    }
+    if header[1] != 0x00 {
+        return Err(FtpError::ProxyError(socks5_reply_message(header[1]).to_string()));
+    }
+
+    let ip = match header[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        },
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        SOCKS5_ATYP_DOMAIN => None,
+        atyp => return Err(FtpError::ProxyError(format!("unsupported address type {}", atyp))),
+    };
+    let host = match ip {
+        Some(_) => String::new(),
+        None => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut host = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut host).await?;
+            String::from_utf8_lossy(&host).into_owned()
+        },
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+
+    Ok(match ip {
+        Some(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        None => TargetAddr::Domain(host, port),
+    })
+}
+
+/// Ask the HTTP proxy connected with `stream` to open a tunnel towards `target` with the `CONNECT` method
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+pub async fn http_connect(stream: &mut TcpStream, credentials: Option<&ProxyCredentials>, target: &TargetAddr) -> FtpResult<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(credentials) = credentials {
+        trace!("HTTP proxy authentication as '{}'", credentials.username);
+        let token = base64_encode(format!("{}:{}", credentials.username, credentials.password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response one byte at a time: what follows the headers belongs to the tunnel
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= HTTP_MAX_RESPONSE_SIZE {
+            return Err(FtpError::ProxyError("response headers are too long".to_string()));
+        }
+        stream.read_exact(&mut byte).await?;
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => {
+            debug!("HTTP proxy connected to {} ({})", target, status_line);
+            Ok(())
+        },
+        _ => Err(FtpError::ProxyError(status_line.to_string())),
+    }
+}
+
+/// Encode `data` with the standard base64 alphabet, with padding
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Description of SOCKS5 reply codes (RFC 1928, section 6)
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown SOCKS error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn should_parse_target_addr() {
+        assert_eq!(
+            TargetAddr::parse("127.0.0.1:2121", 21).unwrap(),
+            TargetAddr::Ip("127.0.0.1:2121".parse().unwrap())
+        );
+        assert_eq!(
+            TargetAddr::parse("127.0.0.1", 21).unwrap(),
+            TargetAddr::Ip("127.0.0.1:21".parse().unwrap())
+        );
+        assert_eq!(
+            TargetAddr::parse("[::1]:2121", 21).unwrap(),
+            TargetAddr::Ip("[::1]:2121".parse().unwrap())
+        );
+        assert_eq!(TargetAddr::parse("[::1]", 21).unwrap(), TargetAddr::Ip("[::1]:21".parse().unwrap()));
+        assert_eq!(TargetAddr::parse("::1", 21).unwrap(), TargetAddr::Ip("[::1]:21".parse().unwrap()));
+        assert_eq!(
+            TargetAddr::parse("ftp.example.com", 21).unwrap(),
+            TargetAddr::Domain("ftp.example.com".to_string(), 21)
+        );
+        assert_eq!(
+            TargetAddr::parse("ftp.example.com:2121", 21).unwrap(),
+            TargetAddr::Domain("ftp.example.com".to_string(), 2121)
+        );
+        assert!(TargetAddr::parse("ftp.example.com:ftp", 21).is_err());
+        assert!(TargetAddr::parse(":21", 21).is_err());
+    }
+
+    #[test]
+    fn should_use_default_proxy_ports() {
+        let server = TargetAddr::Domain("ftp.example.com".to_string(), 21);
+        let socks5 = ProxyCtx {
+            proxy: Proxy::Socks5 { addr: "proxy.local".to_string(), credentials: None },
+            server: server.clone(),
+        };
+        assert_eq!(socks5.default_port(), 1080);
+        let http = ProxyCtx {
+            proxy: Proxy::Http { addr: "proxy.local".to_string(), credentials: None },
+            server,
+        };
+        assert_eq!(http.default_port(), 8080);
+    }
+
+    #[test]
+    fn should_encode_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"proxy:secret"), "cHJveHk6c2VjcmV0");
+    }
+}
+
+}
@@ -0,0 +1,334 @@
+//! # Reconnect
+//!
+//! This module exposes a session wrapper which re-establishes the connection when it's lost
+
+maybe_async_cfg::content! {
+
+#![maybe_async_cfg::default(
+    idents(
+        async_std(sync="std", async),
+        Read(use),
+        Write(use),
+        FtpStream,
+        FtpStreamBuilder,
+        ReconnectingFtpStream,
+    ),
+)]
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use super::{FtpStream, FtpStreamBuilder};
+use crate::types::{FileType, FtpError, FtpResult, Mode, ReconnectPolicy};
+use crate::Status;
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+use async_std::io::{Read, Write};
+#[cfg(feature = "async")]
+use async_std::prelude::*;
+
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// FTP session which reconnects when the connection is lost.
+///
+/// The session is set up by a `FtpStreamBuilder`, which is used again to reconnect; the working directory,
+/// the transfer type and the mode changed through this wrapper are remembered and restored as well.
+/// When an operation fails because the connection has been lost (closed or reset by the peer, or `421` replied
+/// by the server), the session is re-established according to the `ReconnectPolicy`. Idempotent operations are
+/// then retried transparently; downloads are resumed with `REST` if the transfer type is binary, and fail otherwise.
+/// Other errors, such as timeouts and TLS errors, are returned as they are.
+///
+/// Other operations are available through `get_mut()`, but they're not retried, and the state they change
+/// is not restored on reconnection.
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+#[derive(Debug)]
+pub struct ReconnectingFtpStream {
+    builder: FtpStreamBuilder,
+    policy: ReconnectPolicy,
+    stream: FtpStream,
+}
+
+#[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
+impl ReconnectingFtpStream {
+    /// Set up the session described by `builder`
+    pub async fn connect(builder: FtpStreamBuilder, policy: ReconnectPolicy) -> FtpResult<Self> {
+        let stream = builder.connect().await?;
+        Ok(Self { builder, policy, stream })
+    }
+
+    /// Returns a reference to the current session
+    pub fn get_ref(&self) -> &FtpStream {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the current session.
+    /// Operations performed on it are not retried.
+    pub fn get_mut(&mut self) -> &mut FtpStream {
+        &mut self.stream
+    }
+
+    /// Drop the current session and set up a new one
+    pub async fn reconnect(&mut self) -> FtpResult<()> {
+        debug!("Reconnecting");
+        self.stream = self.builder.connect().await?;
+        Ok(())
+    }
+
+    /// Change the current directory to the path specified; the directory is restored on reconnection
+    pub async fn cwd<S: AsRef<str>>(&mut self, path: S) -> FtpResult<()> {
+        let mut attempt = 0;
+        let pwd = loop {
+            let result = match self.stream.cwd(path.as_ref()).await {
+                Ok(()) => self.stream.pwd().await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => break result?,
+            }
+        };
+        self.builder = self.builder.clone().working_directory(pwd);
+        Ok(())
+    }
+
+    /// Gets the current directory
+    pub async fn pwd(&mut self) -> FtpResult<String> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.pwd().await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Sets the type of file to be transferred; the type is restored on reconnection
+    pub async fn transfer_type(&mut self, file_type: FileType) -> FtpResult<()> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.transfer_type(file_type.clone()).await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => break result?,
+            }
+        }
+        self.builder = self.builder.clone().transfer_type(file_type);
+        Ok(())
+    }
+
+    /// Set the data connection mode; the mode is restored on reconnection
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.stream.set_mode(mode);
+        self.builder = self.builder.clone().mode(mode);
+    }
+
+    /// Execute `LIST` (see `FtpStream::list`)
+    pub async fn list(&mut self, pathname: Option<&str>) -> FtpResult<Vec<String>> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.list(pathname).await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Execute `NLST` (see `FtpStream::nlst`)
+    pub async fn nlst(&mut self, pathname: Option<&str>) -> FtpResult<Vec<String>> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.nlst(pathname).await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Retrieves the modification time of the file at `pathname` (see `FtpStream::mdtm`)
+    pub async fn mdtm<S: AsRef<str>>(&mut self, pathname: S) -> FtpResult<DateTime<Utc>> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.mdtm(pathname.as_ref()).await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Retrieves the size of the file in bytes at `pathname` (see `FtpStream::size`)
+    pub async fn size<S: AsRef<str>>(&mut self, pathname: S) -> FtpResult<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.stream.size(pathname.as_ref()).await {
+                Err(err) if self.can_retry(&err, attempt) => self.recover(&mut attempt).await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Download `file_name` into `writer`, returning the amount of bytes written.
+    /// If the connection is lost, the download is resumed with `REST` from the last byte written;
+    /// since offsets of text transfers differ between client and server, the transfer type must be set to binary
+    /// (with `transfer_type` or with the builder), otherwise the download fails once data has been written.
+    pub async fn retr_into<S, W>(&mut self, file_name: S, writer: &mut W) -> FtpResult<u64>
+    where
+        S: AsRef<str>,
+        W: Write + std::marker::Unpin,
+    {
+        let mut attempt = 0;
+        let mut written: u64 = 0;
+        loop {
+            match Self::retr_from(&mut self.stream, file_name.as_ref(), writer, &mut written).await {
+                Err(err) if self.can_retry(&err, attempt) && (written == 0 || self.is_binary()) => {
+                    self.recover(&mut attempt).await?
+                },
+                result => return result.map(|_| written),
+            }
+        }
+    }
+
+    /// Quits the current FTP session
+    pub async fn quit(self) -> FtpResult<()> {
+        self.stream.quit().await
+    }
+
+    /// Download `file_name` starting at `written`, updating it as data is written
+    async fn retr_from<W>(stream: &mut FtpStream, file_name: &str, writer: &mut W, written: &mut u64) -> FtpResult<()>
+    where
+        W: Write + std::marker::Unpin,
+    {
+        if *written > 0 {
+            stream.resume_transfer(*written as usize).await?;
+        }
+        let mut data_stream = stream.retr_as_stream(file_name).await?;
+        let mut buffer = vec![0; 65536];
+        loop {
+            let size = match data_stream.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            writer.write_all(&buffer[..size]).await?;
+            *written += size as u64;
+        }
+        stream.finalize_retr_data_stream(data_stream).await
+    }
+
+    /// Returns whether `err` means that the session has been lost and the operation can be retried
+    fn can_retry(&self, err: &FtpError, attempt: usize) -> bool {
+        Self::is_connection_lost(err) && attempt < self.policy.max_retries
+    }
+
+    /// Returns whether `err` means that the connection has been lost (or refused, while reconnecting).
+    /// Timeouts, TLS and configuration errors may happen again or in the middle of a transfer, so they're not retried.
+    fn is_connection_lost(err: &FtpError) -> bool {
+        match err {
+            FtpError::UnexpectedResponse(response) => response.status == Status::NotAvailable,
+            // The control connection has been closed by the server
+            FtpError::BadResponse => true,
+            FtpError::ConnectionError(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            FtpError::ConnectFailed(errors) => errors.iter().any(|(_, err)| Self::is_connection_lost(err)),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the transfer type is binary, so that transfers can be resumed with `REST`
+    fn is_binary(&self) -> bool {
+        matches!(self.builder.get_transfer_type(), Some(FileType::Binary | FileType::Image))
+    }
+
+    /// Wait for the backoff delay and reconnect, until the session is re-established or the attempts are exhausted
+    async fn recover(&mut self, attempt: &mut usize) -> FtpResult<()> {
+        loop {
+            let delay = self.policy.delay(*attempt);
+            *attempt += 1;
+            debug!("Connection lost; reconnecting in {:?} (attempt {})", delay, attempt);
+            Self::sleep(delay).await;
+            match self.reconnect().await {
+                Err(err) if self.can_retry(&err, *attempt) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[maybe_async_cfg::only_if(sync)]
+    fn sleep(delay: Duration) {
+        std::thread::sleep(delay)
+    }
+
+    #[maybe_async_cfg::only_if(async)]
+    async fn sleep(delay: Duration) {
+        async_std::task::sleep(delay).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::mock::{Action, MockServer};
+
+    use pretty_assertions::assert_eq;
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_reconnect_and_restore_session() {
+        crate::log_init();
+        let server = MockServer::start_sessions("127.0.0.1:0", vec![
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("CWD pub", Action::Reply("250 Directory changed")),
+                ("PWD", Action::Reply("257 \"/pub\" is the current directory")),
+                ("SIZE a.txt", Action::Reply("421 Service not available, closing control connection")),
+            ],
+            vec![
+                ("USER test", Action::Reply("331 Password required")),
+                ("PASS secret", Action::Reply("230 Logged in")),
+                ("CWD /pub", Action::Reply("250 Directory changed")),
+                ("SIZE a.txt", Action::Reply("213 42")),
+                ("QUIT", Action::Reply("221 Goodbye")),
+            ],
+        ]);
+        let builder = FtpStreamBuilder::new(server.addr().to_string()).login("test", "secret");
+        let policy = ReconnectPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            multiplier: 2,
+        };
+        let mut stream = ReconnectingFtpStream::connect(builder, policy).await.unwrap();
+        assert!(stream.cwd("pub").await.is_ok());
+        assert_eq!(stream.size("a.txt").await.unwrap(), 42);
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+
+    #[maybe_async_cfg::maybe(sync(feature="sync", test), async(feature="async", async_attributes::test))]
+    async fn should_not_reconnect_on_timeout() {
+        crate::log_init();
+        let server = MockServer::start_sessions("127.0.0.1:0", vec![vec![
+            ("SIZE a.txt", Action::Ignore),
+            ("QUIT", Action::Reply("221 Goodbye")),
+        ]]);
+        let builder = FtpStreamBuilder::new(server.addr().to_string()).control_timeout(Duration::from_millis(200));
+        let policy = ReconnectPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            multiplier: 2,
+        };
+        let mut stream = ReconnectingFtpStream::connect(builder, policy).await.unwrap();
+        assert!(matches!(stream.size("a.txt").await.err().unwrap(), FtpError::Timeout));
+        assert!(stream.quit().await.is_ok());
+        server.join();
+    }
+}
+
+}
@@ -0,0 +1,1084 @@
+//! # TLS
+//!
+//! This module exposes the traits implemented by the TLS backends (native-tls and rustls)
+//! used to secure the control and data connections
+
+#[cfg(feature = "_secure")]
+use crate::types::{FtpError, FtpResult};
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+use crate::types::CertificateRejection;
+
+#[cfg(feature = "_secure")]
+use std::fmt;
+#[cfg(feature = "_secure")]
+use std::io;
+#[cfg(feature = "_secure")]
+use std::sync::Arc;
+
+// -- sync
+
+/// TLS stream established by a `TlsBackend` over a control or data connection
+#[cfg(feature = "_sync-tls")]
+pub trait TlsBackendStreamSync: std::io::Read + std::io::Write + Send + fmt::Debug {
+    /// Returns a reference to the underlying TCP stream
+    fn get_ref(&self) -> &std::net::TcpStream;
+
+    /// Returns the certificate chain presented by the server (DER encoded, leaf certificate first).
+    /// Backends which only expose the leaf certificate return it alone.
+    fn peer_certificates(&self) -> Vec<Vec<u8>>;
+
+    /// Returns the negotiated protocol version (e.g. `TLSv1_3`), if the backend exposes it
+    fn protocol_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the negotiated cipher suite (e.g. `TLS13_AES_256_GCM_SHA384`), if the backend exposes it
+    fn cipher_suite(&self) -> Option<String> {
+        None
+    }
+
+    /// Send the TLS `close_notify` alert
+    fn shutdown(&mut self) -> io::Result<()>;
+}
+
+/// TLS backend: it performs the TLS handshake on the control connection and on every data connection
+#[cfg(feature = "_sync-tls")]
+pub trait TlsBackendSync: Send + Sync + fmt::Debug {
+    /// Perform the TLS handshake over `stream`, verifying the server certificate for `domain`
+    fn connect(&self, domain: &str, stream: std::net::TcpStream) -> FtpResult<Box<dyn TlsBackendStreamSync>>;
+
+    /// Returns a backend with a TLS session cache of its own, used for the control and data connections
+    /// of a single FTP session, so that data connections resume the session of the control connection.
+    /// Returns `None` if the backend doesn't support session resumption.
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendSync>> {
+        None
+    }
+}
+
+#[cfg(feature = "sync-secure")]
+impl TlsBackendSync for native_tls::TlsConnector {
+    fn connect(&self, domain: &str, stream: std::net::TcpStream) -> FtpResult<Box<dyn TlsBackendStreamSync>> {
+        Ok(Box::new(native_tls::TlsConnector::connect(self, domain, stream)?))
+    }
+}
+
+#[cfg(feature = "sync-secure")]
+impl TlsBackendStreamSync for native_tls::TlsStream<std::net::TcpStream> {
+    fn get_ref(&self) -> &std::net::TcpStream {
+        native_tls::TlsStream::get_ref(self)
+    }
+
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        match native_tls::TlsStream::peer_certificate(self) {
+            Ok(Some(cert)) => cert.to_der().map(|der| vec![der]).unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        native_tls::TlsStream::shutdown(self)
+    }
+}
+
+#[cfg(feature = "sync-rustls")]
+impl TlsBackendSync for RustlsConnector {
+    fn connect(&self, domain: &str, stream: std::net::TcpStream) -> FtpResult<Box<dyn TlsBackendStreamSync>> {
+        let connection = rustls::ClientConnection::new(self.config.clone(), Self::server_name(domain)?)?;
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        // Complete the handshake right away, so that its errors are reported here
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(Box::new(stream))
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendSync>> {
+        Some(Arc::new(self.with_session_cache()))
+    }
+}
+
+#[cfg(feature = "sync-rustls")]
+impl TlsBackendStreamSync for rustls::StreamOwned<rustls::ClientConnection, std::net::TcpStream> {
+    fn get_ref(&self) -> &std::net::TcpStream {
+        &self.sock
+    }
+
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        rustls_peer_certificates(&self.conn)
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        rustls_protocol_version(&self.conn)
+    }
+
+    fn cipher_suite(&self) -> Option<String> {
+        rustls_cipher_suite(&self.conn)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+// -- async
+
+/// TLS stream established by a `TlsBackend` over a control or data connection.
+/// The TLS `close_notify` alert is sent when the stream is closed.
+#[cfg(feature = "_async-tls")]
+#[async_trait::async_trait]
+pub trait TlsBackendStreamAsync: async_std::io::Read + async_std::io::Write + Unpin + Send + fmt::Debug {
+    /// Returns a reference to the underlying TCP stream
+    fn get_ref(&self) -> &async_std::net::TcpStream;
+
+    /// Returns the certificate chain presented by the server (DER encoded, leaf certificate first).
+    /// Backends which only expose the leaf certificate return it alone.
+    fn peer_certificates(&self) -> Vec<Vec<u8>>;
+
+    /// Returns the negotiated protocol version (e.g. `TLSv1_3`), if the backend exposes it
+    fn protocol_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the negotiated cipher suite (e.g. `TLS13_AES_256_GCM_SHA384`), if the backend exposes it
+    fn cipher_suite(&self) -> Option<String> {
+        None
+    }
+
+    /// Send the TLS `close_notify` alert, keeping the TCP connection open (e.g. for `CCC`)
+    async fn shutdown(&mut self) -> io::Result<()>;
+}
+
+/// TLS backend: it performs the TLS handshake on the control connection and on every data connection
+#[cfg(feature = "_async-tls")]
+#[async_trait::async_trait]
+pub trait TlsBackendAsync: Send + Sync + fmt::Debug {
+    /// Perform the TLS handshake over `stream`, verifying the server certificate for `domain`
+    async fn connect(
+        &self,
+        domain: &str,
+        stream: async_std::net::TcpStream,
+    ) -> FtpResult<Box<dyn TlsBackendStreamAsync>>;
+
+    /// Returns a backend with a TLS session cache of its own, used for the control and data connections
+    /// of a single FTP session, so that data connections resume the session of the control connection.
+    /// Returns `None` if the backend doesn't support session resumption.
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendAsync>> {
+        None
+    }
+}
+
+#[cfg(feature = "async-secure")]
+#[async_trait::async_trait]
+impl TlsBackendAsync for async_native_tls::TlsConnector {
+    async fn connect(
+        &self,
+        domain: &str,
+        stream: async_std::net::TcpStream,
+    ) -> FtpResult<Box<dyn TlsBackendStreamAsync>> {
+        Ok(Box::new(async_native_tls::TlsConnector::connect(self, domain, stream).await?))
+    }
+}
+
+#[cfg(feature = "async-secure")]
+#[async_trait::async_trait]
+impl TlsBackendStreamAsync for async_native_tls::TlsStream<async_std::net::TcpStream> {
+    fn get_ref(&self) -> &async_std::net::TcpStream {
+        async_native_tls::TlsStream::get_ref(self)
+    }
+
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        match async_native_tls::TlsStream::peer_certificate(self) {
+            Ok(Some(cert)) => cert.to_der().map(|der| vec![der]).unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        // Closing the stream only performs the TLS shutdown; the TCP connection stays open
+        async_std::io::WriteExt::close(self).await
+    }
+}
+
+#[cfg(feature = "async-rustls")]
+#[async_trait::async_trait]
+impl TlsBackendAsync for RustlsConnector {
+    async fn connect(
+        &self,
+        domain: &str,
+        stream: async_std::net::TcpStream,
+    ) -> FtpResult<Box<dyn TlsBackendStreamAsync>> {
+        let connector = futures_rustls::TlsConnector::from(self.config.clone());
+        Ok(Box::new(connector.connect(Self::server_name(domain)?, stream).await?))
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendAsync>> {
+        Some(Arc::new(self.with_session_cache()))
+    }
+}
+
+#[cfg(feature = "async-rustls")]
+#[async_trait::async_trait]
+impl TlsBackendStreamAsync for futures_rustls::client::TlsStream<async_std::net::TcpStream> {
+    fn get_ref(&self) -> &async_std::net::TcpStream {
+        futures_rustls::client::TlsStream::get_ref(self).0
+    }
+
+    fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        rustls_peer_certificates(futures_rustls::client::TlsStream::get_ref(self).1)
+    }
+
+    fn protocol_version(&self) -> Option<String> {
+        rustls_protocol_version(futures_rustls::client::TlsStream::get_ref(self).1)
+    }
+
+    fn cipher_suite(&self) -> Option<String> {
+        rustls_cipher_suite(futures_rustls::client::TlsStream::get_ref(self).1)
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        // Closing the stream would also close the TCP connection: just queue the alert and write it
+        self.get_mut().1.send_close_notify();
+        async_std::io::WriteExt::flush(self).await
+    }
+}
+
+// -- session details
+
+/// Details of an established TLS session
+#[cfg(feature = "_secure")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Negotiated protocol version (e.g. `TLSv1_3`); `None` if the backend doesn't expose it (native-tls)
+    pub protocol_version: Option<String>,
+    /// Negotiated cipher suite (e.g. `TLS13_AES_256_GCM_SHA384`); `None` if the backend doesn't expose it (native-tls)
+    pub cipher_suite: Option<String>,
+    /// Certificate chain presented by the server (DER encoded, leaf certificate first);
+    /// native-tls only exposes the leaf certificate
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// Server name sent with SNI and used to verify the server certificate
+    pub server_name: String,
+}
+
+// -- certificate verification
+
+/// Check of the certificate chain presented by the server, performed after every TLS handshake
+/// of the session (control and data connections) by `VerifyingTlsBackend`.
+///
+/// It's implemented by `CertificatePin`, by sets of pins (`CertificatePinSet`) and by closures.
+#[cfg(feature = "_secure")]
+pub trait CertificateVerifier: Send + Sync {
+    /// Check `chain` (DER encoded, leaf certificate first); returns the reason why it's rejected, if so
+    fn verify(&self, chain: &[Vec<u8>]) -> Result<(), String>;
+}
+
+#[cfg(feature = "_secure")]
+impl<F> CertificateVerifier for F
+where
+    F: Fn(&[Vec<u8>]) -> Result<(), String> + Send + Sync,
+{
+    fn verify(&self, chain: &[Vec<u8>]) -> Result<(), String> {
+        self(chain)
+    }
+}
+
+/// Pin of the server certificate: the leaf certificate presented by the server must match it
+#[cfg(feature = "_secure")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// SHA-256 fingerprint of the whole certificate
+    Sha256([u8; 32]),
+    /// SHA-256 hash of the public key (`SubjectPublicKeyInfo`), which survives certificate renewals with the same key
+    SpkiSha256([u8; 32]),
+}
+
+#[cfg(feature = "_secure")]
+impl CertificatePin {
+    /// Parse an hexadecimal SHA-256 certificate fingerprint, such as the ones printed by
+    /// `openssl x509 -fingerprint -sha256` (bytes may be separated by `:`)
+    pub fn from_sha256_fingerprint(fingerprint: &str) -> Option<Self> {
+        let digits: Vec<u8> = fingerprint.bytes().filter(|b| *b != b':').collect();
+        if digits.len() != 64 {
+            return None;
+        }
+        let mut hash = [0; 32];
+        for (byte, pair) in hash.iter_mut().zip(digits.chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self::Sha256(hash))
+    }
+
+    /// Pin matching `cert` (DER encoded)
+    pub fn of_certificate(cert: &[u8]) -> Self {
+        Self::Sha256(sha256(cert))
+    }
+
+    /// Pin matching the public key of `cert` (DER encoded); returns `None` if `cert` can't be parsed
+    pub fn of_public_key(cert: &[u8]) -> Option<Self> {
+        subject_public_key_info(cert).map(|spki| Self::SpkiSha256(sha256(spki)))
+    }
+
+    /// Returns whether `cert` (DER encoded) matches the pin
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            Self::Sha256(hash) => sha256(cert) == *hash,
+            Self::SpkiSha256(hash) => subject_public_key_info(cert).map(|spki| sha256(spki) == *hash).unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(feature = "_secure")]
+impl CertificateVerifier for CertificatePin {
+    fn verify(&self, chain: &[Vec<u8>]) -> Result<(), String> {
+        CertificatePinSet(vec![*self]).verify(chain)
+    }
+}
+
+/// Set of pins: the leaf certificate must match any of them (e.g. the current and the next certificate
+/// during a rotation)
+#[cfg(feature = "_secure")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificatePinSet(pub Vec<CertificatePin>);
+
+#[cfg(feature = "_secure")]
+impl CertificateVerifier for CertificatePinSet {
+    fn verify(&self, chain: &[Vec<u8>]) -> Result<(), String> {
+        let leaf = chain.first().ok_or_else(|| "the server presented no certificate".to_string())?;
+        if self.0.iter().any(|pin| pin.matches(leaf)) {
+            Ok(())
+        } else {
+            Err(format!("certificate with SHA-256 fingerprint {} doesn't match the pinned ones", hex(&sha256(leaf))))
+        }
+    }
+}
+
+/// TLS backend which checks the certificate chain presented by the server with a `CertificateVerifier`
+/// after each handshake of `backend`. Since the backend of the control connection is also used for the
+/// data connections, every channel of the session is checked; a rejected chain fails the operation with
+/// `FtpError::CertificateRejected`.
+///
+/// The verification is performed in addition to the one of `backend`: to accept self-signed certificates
+/// with pinning, the validation of `backend` must be disabled (`danger_accept_invalid_certs` for native-tls).
+/// With rustls, use `RustlsConnector::with_certificate_verifier` instead, which replaces the validation of rustls.
+#[cfg(feature = "_secure")]
+#[derive(Clone)]
+pub struct VerifyingTlsBackend<B> {
+    backend: B,
+    verifier: Arc<dyn CertificateVerifier>,
+}
+
+#[cfg(feature = "_secure")]
+impl<B> VerifyingTlsBackend<B> {
+    /// Check the certificates accepted by `backend` with `verifier`
+    pub fn new<V: CertificateVerifier + 'static>(backend: B, verifier: V) -> Self {
+        Self { backend, verifier: Arc::new(verifier) }
+    }
+
+    fn check(&self, chain: &[Vec<u8>]) -> FtpResult<()> {
+        self.verifier.verify(chain).map_err(|reason| {
+            error!("Server certificate rejected: {}", reason);
+            FtpError::CertificateRejected(reason)
+        })
+    }
+}
+
+#[cfg(feature = "_secure")]
+impl<B: fmt::Debug> fmt::Debug for VerifyingTlsBackend<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifyingTlsBackend").field("backend", &self.backend).finish()
+    }
+}
+
+#[cfg(feature = "_sync-tls")]
+impl<B: TlsBackendSync + 'static> TlsBackendSync for VerifyingTlsBackend<B> {
+    fn connect(&self, domain: &str, stream: std::net::TcpStream) -> FtpResult<Box<dyn TlsBackendStreamSync>> {
+        let stream = self.backend.connect(domain, stream)?;
+        self.check(&stream.peer_certificates())?;
+        Ok(stream)
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendSync>> {
+        let backend = self.backend.for_session()?;
+        Some(Arc::new(VerifyingTlsBackend { backend, verifier: self.verifier.clone() }))
+    }
+}
+
+#[cfg(feature = "_sync-tls")]
+impl TlsBackendSync for Arc<dyn TlsBackendSync> {
+    fn connect(&self, domain: &str, stream: std::net::TcpStream) -> FtpResult<Box<dyn TlsBackendStreamSync>> {
+        (**self).connect(domain, stream)
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendSync>> {
+        (**self).for_session()
+    }
+}
+
+#[cfg(feature = "_async-tls")]
+#[async_trait::async_trait]
+impl<B: TlsBackendAsync + 'static> TlsBackendAsync for VerifyingTlsBackend<B> {
+    async fn connect(
+        &self,
+        domain: &str,
+        stream: async_std::net::TcpStream,
+    ) -> FtpResult<Box<dyn TlsBackendStreamAsync>> {
+        let stream = self.backend.connect(domain, stream).await?;
+        self.check(&stream.peer_certificates())?;
+        Ok(stream)
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendAsync>> {
+        let backend = self.backend.for_session()?;
+        Some(Arc::new(VerifyingTlsBackend { backend, verifier: self.verifier.clone() }))
+    }
+}
+
+#[cfg(feature = "_async-tls")]
+#[async_trait::async_trait]
+impl TlsBackendAsync for Arc<dyn TlsBackendAsync> {
+    async fn connect(
+        &self,
+        domain: &str,
+        stream: async_std::net::TcpStream,
+    ) -> FtpResult<Box<dyn TlsBackendStreamAsync>> {
+        (**self).connect(domain, stream).await
+    }
+
+    fn for_session(&self) -> Option<Arc<dyn TlsBackendAsync>> {
+        (**self).for_session()
+    }
+}
+
+#[cfg(feature = "_secure")]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(data).into()
+}
+
+#[cfg(feature = "_secure")]
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(":")
+}
+
+/// Read a DER element; returns its tag, the whole element, its content and the remaining input
+#[cfg(feature = "_secure")]
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let size = (first & 0x7f) as usize;
+        if size == 0 || size > 4 || rest.len() < size {
+            return None;
+        }
+        let len = rest[..size].iter().fold(0, |len, byte| (len << 8) | *byte as usize);
+        (len, &rest[size..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = input.len() - rest.len();
+    Some((tag, &input[..header + len], &rest[..len], &rest[len..]))
+}
+
+/// Returns the DER encoded `SubjectPublicKeyInfo` of a DER encoded X.509 certificate
+#[cfg(feature = "_secure")]
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, _, cert, _) = der_element(cert)?;
+    let (_, _, mut tbs, _) = der_element(cert)?;
+    // Optional version
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.3;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.3;
+    }
+    der_element(tbs).map(|(_, spki, _, _)| spki)
+}
+
+// -- client identity
+
+/// Client certificate, with its private key, presented to servers requiring mutual TLS.
+///
+/// The identity is set up on the TLS backend (see `RustlsConnector::with_client_identity` and
+/// `ClientIdentity::to_native_tls`), which secures both the control connection and the data connections,
+/// so the certificate is presented on every channel of the session.
+/// If the server rejects the certificate, the operation fails with `FtpError::SecureError`, which is not recoverable
+/// (see `FtpError::is_recoverable`).
+///
+/// native-tls doesn't tell which alert the server sent: the rejection is reported as a generic `SecureError` when
+/// it happens during the handshake (TLS 1.2), and as a `ConnectionError` when the server sends it once the client
+/// has completed the handshake (TLS 1.3), which fails the first reply read. Use rustls to tell rejections apart.
+#[cfg(feature = "_secure")]
+#[derive(Clone)]
+pub enum ClientIdentity {
+    /// PEM encoded certificate chain (leaf certificate first) and PKCS#8 private key
+    Pem { cert_chain: Vec<u8>, key: Vec<u8> },
+    /// DER encoded PKCS#12 archive, protected by `password`
+    Pkcs12 { der: Vec<u8>, password: String },
+}
+
+#[cfg(feature = "_secure")]
+impl ClientIdentity {
+    /// Client identity from a PEM encoded certificate chain and a PEM encoded PKCS#8 private key
+    pub fn from_pem<C: Into<Vec<u8>>, K: Into<Vec<u8>>>(cert_chain: C, key: K) -> Self {
+        Self::Pem { cert_chain: cert_chain.into(), key: key.into() }
+    }
+
+    /// Client identity from a DER encoded PKCS#12 archive
+    pub fn from_pkcs12<D: Into<Vec<u8>>, S: Into<String>>(der: D, password: S) -> Self {
+        Self::Pkcs12 { der: der.into(), password: password.into() }
+    }
+
+    /// Convert the identity for native-tls, to be set on the `TlsConnector` builder
+    #[cfg(feature = "sync-secure")]
+    pub fn to_native_tls(&self) -> FtpResult<native_tls::Identity> {
+        let identity = match self {
+            Self::Pem { cert_chain, key } => native_tls::Identity::from_pkcs8(cert_chain, key),
+            Self::Pkcs12 { der, password } => native_tls::Identity::from_pkcs12(der, password),
+        };
+        identity.map_err(|err| FtpError::SecureError(format!("invalid client identity: {}", err)))
+    }
+
+    /// Convert the identity for native-tls, to be set on the `TlsConnector` builder
+    #[cfg(all(feature = "async-secure", not(feature = "sync-secure")))]
+    pub fn to_native_tls(&self) -> FtpResult<async_native_tls::Identity> {
+        let identity = match self {
+            Self::Pem { cert_chain, key } => async_native_tls::Identity::from_pkcs8(cert_chain, key),
+            Self::Pkcs12 { der, password } => async_native_tls::Identity::from_pkcs12(der, password),
+        };
+        identity.map_err(|err| FtpError::SecureError(format!("invalid client identity: {}", err)))
+    }
+
+    /// Convert the identity for rustls: certificate chain and private key
+    #[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+    fn to_rustls(&self) -> FtpResult<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+        let invalid = |err: &dyn fmt::Display| FtpError::SecureError(format!("invalid client identity: {}", err));
+        let (cert_chain, key) = match self {
+            Self::Pem { cert_chain, key } => {
+                let cert_chain = rustls_pemfile::certs(&mut cert_chain.as_slice()).map_err(|err| invalid(&err))?;
+                let key = rustls_pemfile::read_all(&mut key.as_slice())
+                    .map_err(|err| invalid(&err))?
+                    .into_iter()
+                    .find_map(|item| match item {
+                        rustls_pemfile::Item::PKCS8Key(key)
+                        | rustls_pemfile::Item::RSAKey(key)
+                        | rustls_pemfile::Item::ECKey(key) => Some(key),
+                        _ => None,
+                    });
+                (cert_chain, key)
+            },
+            Self::Pkcs12 { der, password } => {
+                let pfx = p12::PFX::parse(der).map_err(|err| invalid(&format!("{:?}", err)))?;
+                let cert_chain = pfx.cert_x509_bags(password).map_err(|err| invalid(&format!("{:?}", err)))?;
+                let key = pfx.key_bags(password).map_err(|err| invalid(&format!("{:?}", err)))?.into_iter().next();
+                (cert_chain, key)
+            },
+        };
+        if cert_chain.is_empty() {
+            return Err(invalid(&"no certificate found"));
+        }
+        let key = key.ok_or_else(|| invalid(&"no private key found"))?;
+        Ok((cert_chain.into_iter().map(rustls::Certificate).collect(), rustls::PrivateKey(key)))
+    }
+}
+
+/// The private key and the password are not printed
+#[cfg(feature = "_secure")]
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pem { cert_chain, .. } => f
+                .debug_struct("Pem")
+                .field("cert_chain", &String::from_utf8_lossy(cert_chain))
+                .field("key", &"***")
+                .finish(),
+            Self::Pkcs12 { der, .. } => f
+                .debug_struct("Pkcs12")
+                .field("der", &format!("{} bytes", der.len()))
+                .field("password", &"***")
+                .finish(),
+        }
+    }
+}
+
+// -- rustls
+
+/// rustls backend, configured by a `rustls::ClientConfig`.
+///
+/// Each FTP session gets a TLS session cache of its own, so that data connections resume the session
+/// of the control connection, as required by servers such as vsftpd with `require_ssl_reuse=YES`.
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+#[derive(Debug, Clone)]
+pub struct RustlsConnector {
+    config: Arc<rustls::ClientConfig>,
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl RustlsConnector {
+    /// Number of TLS sessions (and TLS 1.3 tickets) kept for an FTP session
+    const SESSION_CACHE_SIZE: usize = 32;
+
+    /// Create a connector trusting the certificates in `roots`, which authenticates with the provided
+    /// client certificate on the control and data connections
+    pub fn with_client_identity(roots: rustls::RootCertStore, identity: &ClientIdentity) -> FtpResult<Self> {
+        let (cert_chain, key) = identity.to_rustls()?;
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(cert_chain, key)?;
+        Ok(Self::from(config))
+    }
+
+    /// Create a connector which accepts the certificate chains accepted by `verifier` (e.g. a `CertificatePin`
+    /// of a self-signed certificate) instead of validating them against trusted roots.
+    /// The server name isn't checked against the certificate either; rejected chains fail the handshake
+    /// with `FtpError::CertificateRejected`.
+    pub fn with_certificate_verifier<V: CertificateVerifier + 'static>(verifier: V) -> Self {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Self::server_cert_verifier(verifier))
+            .with_no_client_auth();
+        Self::from(config)
+    }
+
+    /// rustls verifier which checks the server certificates with `verifier` only, to build custom configurations
+    /// (e.g. pinning along with a client certificate) with `ConfigBuilder::with_custom_certificate_verifier`
+    pub fn server_cert_verifier<V: CertificateVerifier + 'static>(
+        verifier: V,
+    ) -> Arc<dyn rustls::client::ServerCertVerifier> {
+        Arc::new(RustlsCertificateVerifier(Box::new(verifier)))
+    }
+
+    /// Returns a connector with the same configuration and an empty session cache
+    fn with_session_cache(&self) -> Self {
+        let mut config = (*self.config).clone();
+        config.resumption = rustls::client::Resumption::in_memory_sessions(Self::SESSION_CACHE_SIZE);
+        Self::from(config)
+    }
+
+    fn server_name(domain: &str) -> FtpResult<rustls::ServerName> {
+        rustls::ServerName::try_from(domain).map_err(|err| FtpError::SecureError(format!("{}: {}", domain, err)))
+    }
+}
+
+/// Server certificate verifier of rustls delegating to a `CertificateVerifier`
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+struct RustlsCertificateVerifier(Box<dyn CertificateVerifier>);
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl rustls::client::ServerCertVerifier for RustlsCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let chain: Vec<Vec<u8>> = std::iter::once(end_entity)
+            .chain(intermediates)
+            .map(|cert| cert.0.clone())
+            .collect();
+        match self.0.verify(&chain) {
+            Ok(()) => Ok(rustls::client::ServerCertVerified::assertion()),
+            Err(reason) => {
+                error!("Server certificate rejected: {}", reason);
+                Err(rustls::Error::InvalidCertificate(rustls::CertificateError::Other(Arc::new(
+                    CertificateRejection(reason),
+                ))))
+            },
+        }
+    }
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+fn rustls_peer_certificates(conn: &rustls::ClientConnection) -> Vec<Vec<u8>> {
+    conn.peer_certificates()
+        .map(|certs| certs.iter().map(|cert| cert.0.clone()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+fn rustls_protocol_version(conn: &rustls::ClientConnection) -> Option<String> {
+    conn.protocol_version().map(|version| format!("{:?}", version))
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+fn rustls_cipher_suite(conn: &rustls::ClientConnection) -> Option<String> {
+    conn.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()))
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl From<Arc<rustls::ClientConfig>> for RustlsConnector {
+    fn from(config: Arc<rustls::ClientConfig>) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl From<rustls::ClientConfig> for RustlsConnector {
+    fn from(config: rustls::ClientConfig) -> Self {
+        Self::from(Arc::new(config))
+    }
+}
+
+#[cfg(all(test, any(feature = "sync-rustls", feature = "async-rustls")))]
+mod test {
+
+    use super::*;
+    use crate::mock::tls_configs;
+    #[cfg(feature = "sync-rustls")]
+    use rustls::server::StoresServerSessions;
+
+    use pretty_assertions::assert_eq;
+
+    /// Start a TLS server which, for each of the first `connections` clients, sends a greeting
+    /// and reads back a line
+    #[cfg(feature = "sync-rustls")]
+    fn start_server(
+        config: Arc<rustls::ServerConfig>,
+        connections: usize,
+    ) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut lines = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+                let mut stream = std::io::BufReader::new(rustls::StreamOwned::new(connection, stream));
+                stream.get_mut().write_all(b"220 hello\r\n").unwrap();
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                lines.push(line);
+            }
+            lines
+        });
+        (addr, handle)
+    }
+
+    /// Server session store which counts the sessions resumed by clients
+    #[cfg(feature = "sync-rustls")]
+    struct CountingSessionStore {
+        inner: Arc<rustls::server::ServerSessionMemoryCache>,
+        resumed: std::sync::atomic::AtomicUsize,
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    impl CountingSessionStore {
+        fn resumed(&self) -> usize {
+            self.resumed.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        fn count(&self, value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+            if value.is_some() {
+                self.resumed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            value
+        }
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    impl StoresServerSessions for CountingSessionStore {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.count(self.inner.get(key))
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.count(self.inner.take(key))
+        }
+
+        fn can_cache(&self) -> bool {
+            true
+        }
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_connect_with_rustls() {
+        use std::io::{BufRead, Write};
+
+        let (server_config, connector) = tls_configs();
+        let (addr, server) = start_server(server_config, 1);
+        let tcp = std::net::TcpStream::connect(addr).unwrap();
+        let mut stream = std::io::BufReader::new(TlsBackendSync::connect(&connector, "localhost", tcp).unwrap());
+        assert_eq!(stream.get_ref().get_ref().peer_addr().unwrap(), addr);
+        assert_eq!(stream.get_ref().protocol_version().as_deref(), Some("TLSv1_3"));
+        assert!(stream.get_ref().cipher_suite().unwrap().starts_with("TLS13_"));
+        assert_eq!(stream.get_ref().peer_certificates().len(), 1);
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line.as_str(), "220 hello\r\n");
+        stream.get_mut().write_all(b"QUIT\r\n").unwrap();
+        assert!(stream.get_mut().shutdown().is_ok());
+        assert_eq!(server.join().unwrap(), vec!["QUIT\r\n"]);
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_resume_session_of_control_connection() {
+        use std::io::{BufRead, Write};
+
+        let (server_config, connector) = tls_configs();
+        let store = Arc::new(CountingSessionStore {
+            inner: rustls::server::ServerSessionMemoryCache::new(32),
+            resumed: std::sync::atomic::AtomicUsize::new(0),
+        });
+        let mut server_config = (*server_config).clone();
+        server_config.session_storage = store.clone();
+        let (addr, server) = start_server(Arc::new(server_config), 2);
+        let connector = TlsBackendSync::for_session(&connector).unwrap();
+        for _ in 0..2 {
+            let tcp = std::net::TcpStream::connect(addr).unwrap();
+            let mut stream = std::io::BufReader::new(connector.connect("localhost", tcp).unwrap());
+            let mut line = String::new();
+            // Reading also processes the session tickets sent by the server
+            stream.read_line(&mut line).unwrap();
+            stream.get_mut().write_all(b"QUIT\r\n").unwrap();
+            assert!(stream.get_mut().shutdown().is_ok());
+        }
+        assert_eq!(server.join().unwrap().len(), 2);
+        assert!(store.resumed() > 0);
+    }
+
+    /// Returns the configuration of a server requiring a client certificate, the roots trusting the server
+    /// certificate and the client identity accepted by the server
+    fn client_auth_configs() -> (Arc<rustls::ServerConfig>, rustls::RootCertStore, ClientIdentity) {
+        let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_cert_der = rustls::Certificate(server_cert.serialize_der().unwrap());
+        let client_cert = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let client_cert_pem = client_cert.serialize_pem().unwrap();
+        let mut client_roots = rustls::RootCertStore::empty();
+        client_roots.add_parsable_certificates(&rustls_pemfile::certs(&mut client_cert_pem.as_bytes()).unwrap());
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(client_roots).boxed())
+            .with_single_cert(
+                vec![server_cert_der.clone()],
+                rustls::PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&server_cert_der).unwrap();
+        let identity = ClientIdentity::from_pem(client_cert_pem, client_cert.serialize_private_key_pem());
+        (Arc::new(server_config), roots, identity)
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_authenticate_with_client_certificate() {
+        use std::io::{BufRead, Write};
+
+        let (server_config, roots, identity) = client_auth_configs();
+        let connector = RustlsConnector::with_client_identity(roots, &identity).unwrap();
+        let (addr, server) = start_server(server_config, 1);
+        let tcp = std::net::TcpStream::connect(addr).unwrap();
+        let mut stream = std::io::BufReader::new(TlsBackendSync::connect(&connector, "localhost", tcp).unwrap());
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line.as_str(), "220 hello\r\n");
+        stream.get_mut().write_all(b"QUIT\r\n").unwrap();
+        assert!(stream.get_mut().shutdown().is_ok());
+        assert_eq!(server.join().unwrap(), vec!["QUIT\r\n"]);
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_report_rejected_client_certificate() {
+        use std::io::BufRead;
+
+        let (server_config, roots, _) = client_auth_configs();
+        let connector = RustlsConnector::from(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let (addr, _server) = start_server(server_config, 1);
+        let tcp = std::net::TcpStream::connect(addr).unwrap();
+        // With TLS 1.3 the server reports the missing certificate after the client has completed the handshake
+        let result = TlsBackendSync::connect(&connector, "localhost", tcp).and_then(|stream| {
+            let mut line = String::new();
+            std::io::BufReader::new(stream).read_line(&mut line)?;
+            Ok(line)
+        });
+        match result {
+            Err(FtpError::SecureError(msg)) => assert!(msg.contains("client certificate"), "{}", msg),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn should_match_certificate_pins() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let fingerprint = hex(&sha256(&der));
+        assert_eq!(CertificatePin::from_sha256_fingerprint(&fingerprint), Some(CertificatePin::of_certificate(&der)));
+        assert_eq!(
+            CertificatePin::from_sha256_fingerprint(&fingerprint.replace(':', "").to_lowercase()),
+            Some(CertificatePin::of_certificate(&der))
+        );
+        assert!(CertificatePin::from_sha256_fingerprint("AB:CD").is_none());
+        assert!(CertificatePin::of_certificate(&der).matches(&der));
+        assert!(!CertificatePin::Sha256([0; 32]).matches(&der));
+        assert_eq!(subject_public_key_info(&der), Some(cert.get_key_pair().public_key_der().as_slice()));
+        // The public key pin survives a new certificate with the same key
+        let pin = CertificatePin::of_public_key(&der).unwrap();
+        assert!(pin.matches(&cert.serialize_der().unwrap()));
+        assert!(subject_public_key_info(b"garbage").is_none());
+        assert!(CertificatePinSet(vec![CertificatePin::Sha256([0; 32]), pin]).verify(&[der]).is_ok());
+        assert!(pin.verify(&[]).is_err());
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_verify_server_certificate() {
+        use std::io::{BufRead, Write};
+
+        let (server_config, connector) = tls_configs();
+        let (addr, _server) = start_server(server_config, 3);
+        let chain = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = chain.clone();
+        let verifying = VerifyingTlsBackend::new(connector.clone(), move |certs: &[Vec<u8>]| -> Result<(), String> {
+            *recorder.lock().unwrap() = certs.to_vec();
+            Ok(())
+        });
+        let pinned = |backend: &dyn TlsBackendSync| {
+            let tcp = std::net::TcpStream::connect(addr).unwrap();
+            let mut stream = std::io::BufReader::new(backend.connect("localhost", tcp)?);
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            stream.get_mut().write_all(b"QUIT\r\n").unwrap();
+            assert!(stream.get_mut().shutdown().is_ok());
+            FtpResult::Ok(line)
+        };
+        assert!(pinned(&verifying).is_ok());
+        let chain = chain.lock().unwrap().clone();
+        assert_eq!(chain.len(), 1);
+        let pin = CertificatePin::of_certificate(&chain[0]);
+        assert!(pinned(&VerifyingTlsBackend::new(connector.clone(), pin)).is_ok());
+        assert!(matches!(
+            pinned(&VerifyingTlsBackend::new(connector, CertificatePin::Sha256([0; 32]))),
+            Err(FtpError::CertificateRejected(_))
+        ));
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_pin_self_signed_certificate() {
+        use std::io::{BufRead, Write};
+
+        // The certificate of the server isn't trusted by the connectors below
+        let (server_config, _) = tls_configs();
+        let (addr, _server) = start_server(server_config, 3);
+        let connect = |connector: RustlsConnector| {
+            let tcp = std::net::TcpStream::connect(addr).unwrap();
+            let mut stream = std::io::BufReader::new(TlsBackendSync::connect(&connector, "localhost", tcp)?);
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            stream.get_mut().write_all(b"QUIT\r\n").unwrap();
+            FtpResult::Ok(line)
+        };
+        let chain = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = chain.clone();
+        let recording = RustlsConnector::with_certificate_verifier(move |certs: &[Vec<u8>]| -> Result<(), String> {
+            *recorder.lock().unwrap() = certs.to_vec();
+            Ok(())
+        });
+        assert_eq!(connect(recording).unwrap().as_str(), "220 hello\r\n");
+        let pin = CertificatePin::of_certificate(&chain.lock().unwrap()[0]);
+        assert!(connect(RustlsConnector::with_certificate_verifier(pin)).is_ok());
+        assert!(matches!(
+            connect(RustlsConnector::with_certificate_verifier(CertificatePin::Sha256([0; 32]))),
+            Err(FtpError::CertificateRejected(_))
+        ));
+    }
+
+    #[test]
+    fn should_load_client_identity() {
+        let (_, _, identity) = client_auth_configs();
+        let (cert_chain, key) = identity.to_rustls().unwrap();
+        assert_eq!(cert_chain.len(), 1);
+        assert!(format!("{:?}", identity).contains("key: \"***\""));
+        let pkcs12 = p12::PFX::new(&cert_chain[0].0, &key.0, None, "secret", "client").unwrap().to_der();
+        let (pkcs12_chain, _) = ClientIdentity::from_pkcs12(pkcs12.clone(), "secret").to_rustls().unwrap();
+        assert_eq!(pkcs12_chain, cert_chain);
+        assert!(ClientIdentity::from_pkcs12(pkcs12, "wrong").to_rustls().is_err());
+        assert!(ClientIdentity::from_pem("garbage", "garbage").to_rustls().is_err());
+    }
+
+    #[cfg(feature = "sync-rustls")]
+    #[test]
+    fn should_reject_wrong_server_name() {
+        let (server_config, connector) = tls_configs();
+        let (addr, _server) = start_server(server_config, 1);
+        let tcp = std::net::TcpStream::connect(addr).unwrap();
+        assert!(TlsBackendSync::connect(&connector, "ftp.example.com", tcp).is_err());
+    }
+
+    #[cfg(feature = "async-rustls")]
+    #[async_attributes::test]
+    async fn should_connect_with_rustls_async() {
+        use async_std::prelude::*;
+
+        let (server_config, connector) = tls_configs();
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = futures_rustls::TlsAcceptor::from(server_config);
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"220 hello\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+        let tcp = async_std::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsBackendAsync::connect(&connector, "localhost", tcp).await.unwrap();
+        assert_eq!(stream.get_ref().peer_addr().unwrap(), addr);
+        let mut buf = vec![0; 11];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf.as_slice(), b"220 hello\r\n");
+        server.await;
+    }
+
+    #[cfg(feature = "async-rustls")]
+    #[async_attributes::test]
+    async fn should_keep_tcp_open_after_tls_shutdown() {
+        use async_std::prelude::*;
+
+        let (server_config, connector) = tls_configs();
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = futures_rustls::TlsAcceptor::from(server_config);
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            // A clean EOF is only reported once close_notify has been received
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            let (tcp, _) = stream.into_inner();
+            let mut line = String::new();
+            async_std::io::BufReader::new(tcp).read_line(&mut line).await.unwrap();
+            line
+        });
+        let tcp = async_std::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsBackendAsync::connect(&connector, "localhost", tcp).await.unwrap();
+        assert!(stream.shutdown().await.is_ok());
+        let mut tcp = stream.get_ref();
+        tcp.write_all(b"PWD\r\n").await.unwrap();
+        assert_eq!(server.await, "PWD\r\n");
+    }
+}
@@ -6,22 +6,20 @@ maybe_async_cfg::content! {
 
 #![maybe_async_cfg::default(
     idents(
-        async_native_tls(sync="native_tls", async), 
-        async_std(sync="std", async), 
-        Read(use), 
-        Result(use), 
-        Write(use), 
+        async_std(sync="std", async),
+        Read(use),
+        Result(use),
+        Write(use),
         TcpStream(use),
-        TlsStream(use),
+        TlsBackendStream,
         DataStream,
         DataStreamProj,
         TlsStreamWrapper,
-        TlsStreamWrapperProj,
     ),
 )]
 
-#[maybe_async_cfg::maybe(sync(feature = "sync-secure"), async(feature = "async-secure"))]
-use async_native_tls::TlsStream;
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
+use super::tls::TlsBackendStream;
 
 #[maybe_async_cfg::maybe(sync(feature = "sync"), async(feature = "async"))]
 use async_std::{
@@ -29,31 +27,40 @@ use async_std::{
     net::TcpStream
 };
 
-#[cfg(feature = "async")]
-use pin_project::pin_project;
+#[cfg(feature = "_secure")]
+use super::tls::TlsInfo;
+
 #[cfg(feature = "async")]
 use std::pin::Pin;
+#[cfg(feature = "_async-tls")]
+use async_std::io::ReadExt;
+#[cfg(feature = "_secure")]
+use std::time::Duration;
+
+/// Maximum time to wait for the `close_notify` alert of the peer, once ours has been sent.
+/// Many servers don't answer with their own alert after `CCC`
+#[cfg(feature = "_secure")]
+const CLOSE_NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
 
 
 // -- tls stream wrapper to implement drop...
 
 #[maybe_async_cfg::maybe(
-    sync(feature = "sync", replace_feature("_secure", "sync-secure"), drop_attrs(pin)), 
-    async(feature = "async", replace_feature("_secure", "async-secure"), inner("pin_project(project = TlsStreamWrapperProjAsync)")),
+    sync(feature = "sync", replace_feature("_secure", "_sync-tls")),
+    async(feature = "async", replace_feature("_secure", "_async-tls")),
 )]
 #[derive(Debug)]
 /// Tls stream wrapper. This type is a garbage data type used to impl the drop trait for the tls stream.
 /// This allows me to keep returning `Read` and `Write` traits in stream methods
 pub struct TlsStreamWrapper {
-    #[pin]
-    stream: TlsStream<TcpStream>,
-    #[cfg(feature = "sync-secure")]
+    stream: Box<dyn TlsBackendStream>,
+    #[cfg(feature = "_sync-tls")]
     tls_shutdown: bool,
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature = "sync", replace_feature("_secure", "sync-secure")), 
-    async(feature = "async", replace_feature("_secure", "async-secure")),
+    sync(feature = "sync", replace_feature("_secure", "_sync-tls")),
+    async(feature = "async", replace_feature("_secure", "_async-tls")),
 )]
 impl TlsStreamWrapper {
     /// Get underlying tcp stream
@@ -71,27 +78,82 @@ impl TlsStreamWrapper {
         stream
     }
 
-    /// Get underlying tcp stream
+    /// Get underlying tcp stream. The TLS session must have been shut down with `shutdown()` before
     #[maybe_async_cfg::only_if(async)]
     pub(crate) fn tcp_stream(self) -> TcpStream {
         self.stream.get_ref().clone()
     }
 
+    /// Send the TLS `close_notify` alert and wait for the one of the peer, keeping the TCP connection open.
+    /// The wait lasts `CLOSE_NOTIFY_TIMEOUT` at most
+    #[maybe_async_cfg::only_if(sync)]
+    pub(crate) fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown()?;
+        // Don't send close_notify again on drop
+        self.tls_shutdown = false;
+        let read_timeout = self.get_ref().read_timeout()?;
+        self.get_ref().set_read_timeout(Some(CLOSE_NOTIFY_TIMEOUT))?;
+        self.drain();
+        self.get_ref().set_read_timeout(read_timeout)?;
+        trace!("TLS stream shut down");
+        Ok(())
+    }
+
+    /// Send the TLS `close_notify` alert and wait for the one of the peer, keeping the TCP connection open.
+    /// The wait lasts `CLOSE_NOTIFY_TIMEOUT` at most
+    #[maybe_async_cfg::only_if(async)]
+    pub(crate) async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        if async_std::future::timeout(CLOSE_NOTIFY_TIMEOUT, self.drain()).await.is_err() {
+            debug!("No close_notify received within {:?}", CLOSE_NOTIFY_TIMEOUT);
+        }
+        trace!("TLS stream shut down");
+        Ok(())
+    }
+
+    /// Discard what is left of the stream, until the peer closes its side of the TLS session.
+    /// A read error (e.g. the socket timeout expiring, or the connection closed without `close_notify`)
+    /// ends the wait as well: the session is over for us anyway
+    async fn drain(&mut self) {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    debug!("Stopped waiting for close_notify: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+
     /// Get ref to underlying tcp stream
     pub(crate) fn get_ref(&self) -> &TcpStream {
         self.stream.get_ref()
     }
+
+    /// Details of the TLS session, established with `server_name`
+    pub(crate) fn tls_info(&self, server_name: &str) -> TlsInfo {
+        TlsInfo {
+            protocol_version: self.stream.protocol_version(),
+            cipher_suite: self.stream.cipher_suite(),
+            peer_certificates: self.stream.peer_certificates(),
+            server_name: server_name.to_string(),
+        }
+    }
 }
 
 #[maybe_async_cfg::maybe(
-    sync(feature = "sync", replace_feature("_secure", "sync-secure")), 
-    async(feature = "async", replace_feature("_secure", "async-secure")),
+    sync(feature = "sync", replace_feature("_secure", "_sync-tls")),
+    async(feature = "async", replace_feature("_secure", "_async-tls")),
 )]
-impl From<TlsStream<TcpStream>> for TlsStreamWrapper {
-    fn from(stream: TlsStream<TcpStream>) -> Self {
+impl From<Box<dyn TlsBackendStream>> for TlsStreamWrapper {
+    fn from(stream: Box<dyn TlsBackendStream>) -> Self {
         Self {
             stream,
-            #[cfg(feature = "sync-secure")]
+            #[cfg(feature = "_sync-tls")]
             tls_shutdown: true,
         }
     }
@@ -99,7 +161,7 @@ impl From<TlsStream<TcpStream>> for TlsStreamWrapper {
 
 // -- sync
 
-#[maybe_async_cfg::maybe(sync(feature = "sync-secure"), async(feature = "async-secure"))]
+#[maybe_async_cfg::maybe(sync(feature = "_sync-tls"), async(feature = "_async-tls"))]
 #[maybe_async_cfg::only_if(sync)]
 impl Drop for TlsStreamWrapper {
     fn drop(&mut self) {
@@ -113,14 +175,14 @@ impl Drop for TlsStreamWrapper {
     }
 }
 
-#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "sync-secure")))]
+#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "_sync-tls")))]
 impl Read for TlsStreamWrapper {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.stream.read(buf)
     }
 }
 
-#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "sync-secure")))]
+#[maybe_async_cfg::maybe(sync(feature="sync", replace_feature("_secure", "_sync-tls")))]
 impl Write for TlsStreamWrapper {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         self.stream.write(buf)
@@ -133,40 +195,40 @@ impl Write for TlsStreamWrapper {
 
 // -- async
 
-#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "async-secure")))]
+#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "_async-tls")))]
 impl Read for TlsStreamWrapper {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut [u8],
     ) -> std::task::Poll<Result<usize>> {
-        self.project().stream.poll_read(cx, buf)
+        Pin::new(&mut *self.get_mut().stream).poll_read(cx, buf)
     }
 }
 
-#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "async-secure")))]
+#[maybe_async_cfg::maybe(async(feature="async", replace_feature("_secure", "_async-tls")))]
 impl Write for TlsStreamWrapper {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize>> {
-        self.project().stream.poll_write(cx, buf)
+        Pin::new(&mut *self.get_mut().stream).poll_write(cx, buf)
     }
 
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<()>> {
-        self.project().stream.poll_flush(cx)
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
     }
 
     fn poll_close(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<()>> {
-        self.project().stream.poll_close(cx)
+        Pin::new(&mut *self.get_mut().stream).poll_close(cx)
     }
 }
 
-}
\ No newline at end of file
+}
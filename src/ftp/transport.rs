@@ -0,0 +1,84 @@
+//! # Transport
+//!
+//! This module exposes the traits used to run an FTP session over a custom transport
+//! (e.g. a unix socket, a forwarded channel or an in-memory pipe) instead of TCP
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+// -- sync
+
+/// Stream which carries an FTP control or data connection
+#[cfg(feature = "sync")]
+pub trait TransportStreamSync: std::io::Read + std::io::Write + Send + fmt::Debug {}
+
+#[cfg(feature = "sync")]
+impl<T: std::io::Read + std::io::Write + Send + fmt::Debug> TransportStreamSync for T {}
+
+/// Transport of an FTP session: it opens the control connection and all the data connections.
+///
+/// Data connections in passive mode are opened with `connect_data`; in active mode, `listen_data` must return
+/// the address to advertise to the server and a listener which will accept the connection opened by the server.
+/// TLS is not supported over custom transports.
+#[cfg(feature = "sync")]
+pub trait TransportSync: Send + Sync + fmt::Debug {
+    /// Open the control connection
+    fn connect_control(&self) -> io::Result<Box<dyn TransportStreamSync>>;
+
+    /// Open a data connection in passive mode towards `port`.
+    /// `ip` is the address returned by `PASV`, or `None` if the connection must be opened towards
+    /// the host of the control connection (`EPSV`).
+    fn connect_data(&self, ip: Option<IpAddr>, port: u16) -> io::Result<Box<dyn TransportStreamSync>>;
+
+    /// Prepare a data connection in active mode. Not supported by default.
+    fn listen_data(&self) -> io::Result<(SocketAddr, Box<dyn TransportListenerSync>)> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "active mode is not supported by this transport"))
+    }
+}
+
+/// Listener waiting for a data connection in active mode
+#[cfg(feature = "sync")]
+pub trait TransportListenerSync: Send {
+    /// Wait for the server to open the data connection
+    fn accept(self: Box<Self>) -> io::Result<Box<dyn TransportStreamSync>>;
+}
+
+// -- async
+
+/// Stream which carries an FTP control or data connection
+#[cfg(feature = "async")]
+pub trait TransportStreamAsync: async_std::io::Read + async_std::io::Write + Unpin + Send + fmt::Debug {}
+
+#[cfg(feature = "async")]
+impl<T: async_std::io::Read + async_std::io::Write + Unpin + Send + fmt::Debug> TransportStreamAsync for T {}
+
+/// Transport of an FTP session: it opens the control connection and all the data connections.
+///
+/// Data connections in passive mode are opened with `connect_data`; in active mode, `listen_data` must return
+/// the address to advertise to the server and a listener which will accept the connection opened by the server.
+/// TLS is not supported over custom transports.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait TransportAsync: Send + Sync + fmt::Debug {
+    /// Open the control connection
+    async fn connect_control(&self) -> io::Result<Box<dyn TransportStreamAsync>>;
+
+    /// Open a data connection in passive mode towards `port`.
+    /// `ip` is the address returned by `PASV`, or `None` if the connection must be opened towards
+    /// the host of the control connection (`EPSV`).
+    async fn connect_data(&self, ip: Option<IpAddr>, port: u16) -> io::Result<Box<dyn TransportStreamAsync>>;
+
+    /// Prepare a data connection in active mode. Not supported by default.
+    async fn listen_data(&self) -> io::Result<(SocketAddr, Box<dyn TransportListenerAsync>)> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "active mode is not supported by this transport"))
+    }
+}
+
+/// Listener waiting for a data connection in active mode
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait TransportListenerAsync: Send {
+    /// Wait for the server to open the data connection
+    async fn accept(self: Box<Self>) -> io::Result<Box<dyn TransportStreamAsync>>;
+}
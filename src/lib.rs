@@ -61,6 +61,14 @@
 //! compiled with feature `secure` enabled which requires
 //! [rust-native-tls](https://github.com/sfackler/rust-native-tls).
 //!
+//! As an alternative to native-tls (e.g. for static musl builds), [rustls](https://github.com/rustls/rustls)
+//! can be used by enabling the `sync-rustls` or `async-rustls` feature: pass a `RustlsConnector`,
+//! built from a `rustls::ClientConfig`, to `into_secure`.
+//!
+//! Client certificates are loaded as a `ClientIdentity` into the TLS backend, which presents them
+//! on the control and data connections alike. Server certificates can be pinned, or checked by a custom
+//! `CertificateVerifier`, by wrapping the backend in a `VerifyingTlsBackend`.
+//!
 //! The client uses explicit mode for connecting FTPS what means you should
 //! connect the server as usually and then switch to the secure mode (TLS is used).
 //! For better security it's the good practice to switch to the secure mode
@@ -131,6 +139,9 @@ mod utils;
 #[cfg(feature = "support-ftpclient")]
 pub mod callbacks;
 
+#[cfg(test)]
+mod mock;
+
 // -- public
 pub mod list;
 pub mod types;
@@ -141,22 +152,52 @@ pub extern crate native_tls;
 // -- async deps
 #[cfg(feature = "async-secure")]
 pub extern crate async_native_tls;
+// -- rustls deps
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+pub extern crate rustls;
 
 #[cfg(feature = "async")]
 pub mod async_ftp {
     pub use crate::ftp::FtpStreamAsync as FtpStream;
+    pub use crate::ftp::FtpStreamBuilderAsync as FtpStreamBuilder;
+    pub use crate::ftp::ReconnectingFtpStreamAsync as ReconnectingFtpStream;
+    pub use crate::ftp::{FtpPoolAsync as FtpPool, PooledFtpStreamAsync as PooledFtpStream};
+    #[cfg(feature = "_async-tls")]
+    pub use crate::ftp::{TlsBackendAsync as TlsBackend, TlsBackendStreamAsync as TlsBackendStream};
+    pub use crate::ftp::{
+        TransportAsync as Transport, TransportListenerAsync as TransportListener,
+        TransportStreamAsync as TransportStream,
+    };
 }
 #[cfg(feature = "sync")]
 pub mod sync_ftp {
     pub use crate::ftp::FtpStreamSync as FtpStream;
+    pub use crate::ftp::FtpStreamBuilderSync as FtpStreamBuilder;
+    pub use crate::ftp::ReconnectingFtpStreamSync as ReconnectingFtpStream;
+    pub use crate::ftp::{FtpPoolSync as FtpPool, PooledFtpStreamSync as PooledFtpStream};
+    #[cfg(feature = "_sync-tls")]
+    pub use crate::ftp::{TlsBackendStreamSync as TlsBackendStream, TlsBackendSync as TlsBackend};
+    pub use crate::ftp::{
+        TransportListenerSync as TransportListener, TransportStreamSync as TransportStream,
+        TransportSync as Transport,
+    };
 }
 
 #[cfg(feature = "sync")]
-pub use crate::sync_ftp::FtpStream;
+pub use crate::sync_ftp::{FtpPool, FtpStream, FtpStreamBuilder, PooledFtpStream, ReconnectingFtpStream};
 
 // -- export (common)
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+pub use ftp::RustlsConnector;
+#[cfg(feature = "_secure")]
+pub use command::ProtectionLevel;
+#[cfg(feature = "_secure")]
+pub use ftp::{CertificatePin, CertificatePinSet, CertificateVerifier, ClientIdentity, TlsInfo, VerifyingTlsBackend};
 pub use status::Status;
-pub use types::{FtpError, FtpResult, Mode};
+pub use types::{
+    ActiveModeConfig, FtpError, FtpProxy, FtpProxyKind, FtpResult, KeepAliveConfig, Mode, PassiveAddressPolicy,
+    PassiveTargetPolicy, Proxy, ProxyCredentials, ReconnectPolicy,
+};
 
 // -- test logging
 #[cfg(test)]
@@ -179,5 +220,8 @@ pub mod test {
     pub const TEST_TLS_SERVER_PASSWORD: &str = "password";
 }
 
-#[cfg(all(feature = "_secure", not(any(feature = "sync-secure", feature = "async-secure"))))]
-compile_error!("feature \"_secure\" is internal and cannot be used alone; use features \"sync-secure\" or \"async-secure\" instead");
\ No newline at end of file
+#[cfg(all(
+    feature = "_secure",
+    not(any(feature = "sync-secure", feature = "async-secure", feature = "sync-rustls", feature = "async-rustls"))
+))]
+compile_error!("feature \"_secure\" is internal and cannot be used alone; use features \"sync-secure\", \"async-secure\", \"sync-rustls\" or \"async-rustls\" instead");
\ No newline at end of file
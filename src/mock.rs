@@ -0,0 +1,571 @@
+//! # Mock
+//!
+//! A scripted FTP server stand-in, used by tests which must not depend on a real FTP server
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+/// What the mock server does once it has received the expected command
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Reply with the provided line
+    Reply(&'static str),
+    /// Don't reply
+    Ignore,
+    /// Open a data listener and reply with a `227` response
+    Pasv,
+    /// Open a data listener and reply with a `227` response, advertising the provided address instead of the real one
+    PasvAs([u8; 4]),
+    /// Open a data listener and reply with a `229` response
+    Epsv,
+    /// Remember the address sent with `PORT` and reply `200`
+    Port,
+    /// Remember the address sent with `EPRT` and reply `200`
+    Eprt,
+    /// Reply `150`, send the provided data over the data connection, close it and reply `226`
+    Send(&'static [u8]),
+    /// Reply `150`, read the data connection until it's closed and reply `226`
+    Receive,
+    /// Open a connection to the active mode listener from `127.0.0.2`, then behave like `Send`
+    SendAfterIntruder(&'static [u8]),
+}
+
+/// Mock FTP server running in its own thread.
+/// Every received command must start with the expected prefix, otherwise the server thread panics.
+pub struct MockServer {
+    addr: SocketAddr,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl MockServer {
+    /// Start a mock server listening on `bind`, which will run the provided script
+    pub fn start(bind: &str, script: Vec<(&'static str, Action)>) -> Self {
+        Self::start_sessions(bind, vec![script])
+    }
+
+    /// Start a mock server listening on `bind`, which will accept a connection for each script
+    /// and run them in order. Each connection is closed once its script is complete.
+    pub fn start_sessions(bind: &str, scripts: Vec<Vec<(&'static str, Action)>>) -> Self {
+        let listener = TcpListener::bind(bind).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut received = Vec::new();
+            for script in scripts {
+                let (stream, _) = listener.accept().unwrap();
+                received.extend(MockSession::new(stream).run(script));
+            }
+            received
+        });
+        Self { addr, handle }
+    }
+
+    /// Address the mock server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the script to complete and return the commands received by the server
+    pub fn join(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+fn write_line<W: Write>(writer: &mut W, line: &str) {
+    writer.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+}
+
+struct MockSession {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    listener: Option<TcpListener>,
+    active_addr: Option<SocketAddr>,
+}
+
+impl MockSession {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+            listener: None,
+            active_addr: None,
+        }
+    }
+
+    fn run(mut self, script: Vec<(&'static str, Action)>) -> Vec<String> {
+        let mut received = Vec::new();
+        self.reply("220 mock server ready");
+        for (expected, action) in script {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let command = line.trim_end().to_string();
+            assert!(
+                command.starts_with(expected),
+                "expected command '{}', got '{}'",
+                expected,
+                command
+            );
+            self.perform(&command, action);
+            received.push(command);
+        }
+        received
+    }
+
+    fn perform(&mut self, command: &str, action: Action) {
+        match action {
+            Action::Reply(line) => self.reply(line),
+            Action::Ignore => {}
+            Action::Pasv => self.pasv([127, 0, 0, 1]),
+            Action::PasvAs(ip) => self.pasv(ip),
+            Action::Epsv => {
+                let ip = self.writer.local_addr().unwrap().ip();
+                let listener = TcpListener::bind(SocketAddr::new(ip, 0)).unwrap();
+                let port = listener.local_addr().unwrap().port();
+                self.listener = Some(listener);
+                self.reply(&format!("229 Entering Extended Passive Mode (|||{}|)", port));
+            }
+            Action::Port => {
+                let args: Vec<u16> = command[5..]
+                    .split(',')
+                    .map(|x| x.parse().unwrap())
+                    .collect();
+                let ip: IpAddr = format!("{}.{}.{}.{}", args[0], args[1], args[2], args[3])
+                    .parse()
+                    .unwrap();
+                self.active_addr = Some(SocketAddr::new(ip, args[4] * 256 + args[5]));
+                self.reply("200 PORT command successful");
+            }
+            Action::Eprt => {
+                let args: Vec<&str> = command[5..].split('|').collect();
+                let ip: IpAddr = args[2].parse().unwrap();
+                self.active_addr = Some(SocketAddr::new(ip, args[3].parse().unwrap()));
+                self.reply("200 EPRT command successful");
+            }
+            Action::Send(data) => {
+                self.reply("150 Opening data connection");
+                let mut stream = self.data_connection();
+                stream.write_all(data).unwrap();
+                drop(stream);
+                self.reply("226 Transfer complete");
+            }
+            Action::SendAfterIntruder(data) => {
+                let addr = self.active_addr.expect("no active address has been received");
+                let intruder = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+                intruder
+                    .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), 0).into())
+                    .unwrap();
+                intruder.connect(&addr.into()).unwrap();
+                let mut intruder: TcpStream = intruder.into();
+                let _ = intruder.write_all(b"intruder data\r\n");
+                self.perform(command, Action::Send(data));
+            }
+            Action::Receive => {
+                self.reply("150 Opening data connection");
+                let mut stream = self.data_connection();
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).unwrap();
+                self.reply("226 Transfer complete");
+            }
+        }
+    }
+
+    fn data_connection(&mut self) -> TcpStream {
+        match (self.listener.take(), self.active_addr.take()) {
+            (Some(listener), _) => listener.accept().unwrap().0,
+            (None, Some(addr)) => TcpStream::connect(addr).unwrap(),
+            (None, None) => panic!("no data connection has been prepared"),
+        }
+    }
+
+    fn pasv(&mut self, ip: [u8; 4]) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        self.listener = Some(listener);
+        self.reply(&format!(
+            "227 Entering Passive Mode ({},{},{},{},{},{})",
+            ip[0],
+            ip[1],
+            ip[2],
+            ip[3],
+            port / 256,
+            port % 256
+        ));
+    }
+
+    fn reply(&mut self, line: &str) {
+        self.writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+    }
+}
+
+/// Mock SOCKS5 proxy running in its own thread, serving a fixed number of connections.
+/// Supports `CONNECT` and `BIND` with IPv4 addresses, with or without username/password authentication.
+pub struct MockSocksProxy {
+    addr: SocketAddr,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl MockSocksProxy {
+    /// Start a mock proxy which will serve `connections` connections.
+    /// If `credentials` are provided, clients must authenticate with them.
+    pub fn start(connections: usize, credentials: Option<(&'static str, &'static str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut relays = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let (request, client, target) = Self::serve(stream, credentials);
+                requests.push(request);
+                relays.push(relay(client.try_clone().unwrap(), target.try_clone().unwrap()));
+                relays.push(relay(target, client));
+            }
+            for relay in relays {
+                relay.join().unwrap();
+            }
+            requests
+        });
+        Self { addr, handle }
+    }
+
+    /// Address the mock proxy is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for all the tunnels to be closed and return the requests received by the proxy (e.g. `CONNECT 127.0.0.1:21`)
+    pub fn join(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+
+    /// Perform the SOCKS5 negotiation; returns the request, the client stream and the target stream
+    fn serve(mut client: TcpStream, credentials: Option<(&str, &str)>) -> (String, TcpStream, TcpStream) {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 5);
+        let mut methods = vec![0u8; header[1] as usize];
+        client.read_exact(&mut methods).unwrap();
+        match credentials {
+            Some((username, password)) => {
+                assert!(methods.contains(&2));
+                client.write_all(&[5, 2]).unwrap();
+                let mut version = [0u8; 2];
+                client.read_exact(&mut version).unwrap();
+                let mut user = vec![0u8; version[1] as usize];
+                client.read_exact(&mut user).unwrap();
+                let mut len = [0u8; 1];
+                client.read_exact(&mut len).unwrap();
+                let mut pass = vec![0u8; len[0] as usize];
+                client.read_exact(&mut pass).unwrap();
+                assert_eq!(user, username.as_bytes());
+                assert_eq!(pass, password.as_bytes());
+                client.write_all(&[1, 0]).unwrap();
+            }
+            None => client.write_all(&[5, 0]).unwrap(),
+        }
+
+        let mut request = [0u8; 10];
+        client.read_exact(&mut request).unwrap();
+        assert_eq!(request[3], 1, "only IPv4 addresses are supported");
+        let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+        let addr = SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([request[8], request[9]]));
+        match request[1] {
+            1 => {
+                let target = TcpStream::connect(addr).unwrap();
+                Self::reply(&mut client, target.local_addr().unwrap());
+                (format!("CONNECT {}", addr), client, target)
+            }
+            2 => {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                Self::reply(&mut client, listener.local_addr().unwrap());
+                let (target, peer) = listener.accept().unwrap();
+                Self::reply(&mut client, peer);
+                (format!("BIND {}", addr), client, target)
+            }
+            command => panic!("unsupported SOCKS command {}", command),
+        }
+    }
+
+    fn reply(client: &mut TcpStream, addr: SocketAddr) {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => panic!("only IPv4 addresses are supported"),
+        };
+        let mut reply = vec![5, 0, 0, 1];
+        reply.extend_from_slice(&ip);
+        reply.extend_from_slice(&addr.port().to_be_bytes());
+        client.write_all(&reply).unwrap();
+    }
+}
+
+/// Mock HTTP proxy running in its own thread, serving a fixed number of `CONNECT` requests.
+pub struct MockHttpProxy {
+    addr: SocketAddr,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl MockHttpProxy {
+    /// Start a mock proxy which will serve `connections` connections.
+    /// If `authorization` is provided, requests must carry it in the `Proxy-Authorization` header,
+    /// otherwise the proxy replies `407`.
+    pub fn start(connections: usize, authorization: Option<&'static str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut relays = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let (request, tunnel) = Self::serve(stream, authorization);
+                requests.push(request);
+                if let Some((client, target)) = tunnel {
+                    relays.push(relay(client.try_clone().unwrap(), target.try_clone().unwrap()));
+                    relays.push(relay(target, client));
+                }
+            }
+            for relay in relays {
+                relay.join().unwrap();
+            }
+            requests
+        });
+        Self { addr, handle }
+    }
+
+    /// Address the mock proxy is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for all the tunnels to be closed and return the request lines received by the proxy
+    pub fn join(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+
+    /// Read the request; returns the request line and, if accepted, the client and target streams
+    fn serve(mut client: TcpStream, authorization: Option<&str>) -> (String, Option<(TcpStream, TcpStream)>) {
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap();
+        let request = request.trim_end().to_string();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end().is_empty() {
+                break;
+            }
+            headers.push(line.trim_end().to_string());
+        }
+
+        if let Some(authorization) = authorization {
+            let expected = format!("Proxy-Authorization: Basic {}", authorization);
+            if !headers.contains(&expected) {
+                client
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .unwrap();
+                return (request, None);
+            }
+        }
+
+        let target: SocketAddr = request.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let target = TcpStream::connect(target).unwrap();
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .unwrap();
+        (request, Some((client, target)))
+    }
+}
+
+/// Copy `from` into `to` until EOF, then shut down the write half of `to`
+fn relay(mut from: TcpStream, mut to: TcpStream) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Write);
+    })
+}
+
+/// Returns the configuration of a server with a self-signed certificate for `localhost`,
+/// and a rustls backend which trusts it
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+pub fn tls_configs() -> (std::sync::Arc<rustls::ServerConfig>, crate::RustlsConnector) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+    let key_der = rustls::PrivateKey(cert.serialize_private_key_der());
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der.clone()], key_der)
+        .unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert_der).unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (std::sync::Arc::new(server_config), crate::RustlsConnector::from(client_config))
+}
+
+/// What the mock implicit FTPS server does once it has received the expected command
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+#[derive(Debug, Clone)]
+pub enum TlsAction {
+    /// Reply with the provided line
+    Reply(&'static str),
+    /// Open a data listener and reply with a `227` response
+    Pasv,
+    /// Reply `150`, send the provided data over a TLS data connection, close it and reply `226`
+    SendTls(&'static [u8]),
+    /// Reply `200`, wait for the close_notify of the client, send our own and go on in clear
+    Ccc,
+    /// Reply `200`, wait for the close_notify of the client and go on in clear without answering it
+    CccWithoutCloseNotify,
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl From<&'static str> for TlsAction {
+    fn from(reply: &'static str) -> Self {
+        Self::Reply(reply)
+    }
+}
+
+/// Control connection of the mock implicit FTPS server, which is in clear after `CCC`
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+enum MockTlsControl {
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+    Plain(TcpStream),
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl MockTlsControl {
+    /// Wait for the close_notify of the client, answer it if `close_notify` is set and return the plain connection
+    fn clear(self, close_notify: bool) -> Self {
+        match self {
+            Self::Tls(mut stream) => {
+                let mut buf = [0; 64];
+                while stream.read(&mut buf).unwrap() > 0 {}
+                if close_notify {
+                    stream.conn.send_close_notify();
+                    while stream.conn.wants_write() {
+                        stream.conn.write_tls(&mut stream.sock).unwrap();
+                    }
+                }
+                Self::Plain(stream.sock)
+            }
+            plain => plain,
+        }
+    }
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl Read for MockTlsControl {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tls(stream) => stream.read(buf),
+            Self::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl Write for MockTlsControl {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tls(stream) => stream.write(buf),
+            Self::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tls(stream) => stream.flush(),
+            Self::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Mock implicit FTPS server running in its own thread: the TLS handshake is performed as soon as
+/// the client connects, then the script of expected command prefixes and actions is run.
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+pub struct MockTlsServer {
+    addr: SocketAddr,
+    handle: JoinHandle<Vec<String>>,
+}
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl MockTlsServer {
+    /// Start a mock server listening on localhost with the provided TLS configuration
+    pub fn start<A>(config: std::sync::Arc<rustls::ServerConfig>, script: Vec<(&'static str, A)>) -> Self
+    where
+        A: Into<TlsAction> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+            let mut stream = BufReader::new(MockTlsControl::Tls(Box::new(rustls::StreamOwned::new(connection, stream))));
+            stream.get_mut().write_all(b"220 mock server ready\r\n").unwrap();
+            let mut data_listener = None;
+            let mut received = Vec::new();
+            for (expected, action) in script {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let command = line.trim_end().to_string();
+                assert!(
+                    command.starts_with(expected),
+                    "expected command '{}', got '{}'",
+                    expected,
+                    command
+                );
+                match action.into() {
+                    TlsAction::Reply(line) => write_line(stream.get_mut(), line),
+                    TlsAction::Pasv => {
+                        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                        let port = listener.local_addr().unwrap().port();
+                        data_listener = Some(listener);
+                        write_line(
+                            stream.get_mut(),
+                            &format!("227 Entering Passive Mode (127,0,0,1,{},{})", port / 256, port % 256),
+                        );
+                    }
+                    TlsAction::SendTls(data) => {
+                        write_line(stream.get_mut(), "150 Opening data connection");
+                        let listener = data_listener.take().expect("no data connection has been prepared");
+                        let (data_stream, _) = listener.accept().unwrap();
+                        let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+                        let mut data_stream = rustls::StreamOwned::new(connection, data_stream);
+                        data_stream.write_all(data).unwrap();
+                        data_stream.conn.send_close_notify();
+                        data_stream.flush().unwrap();
+                        drop(data_stream);
+                        write_line(stream.get_mut(), "226 Transfer complete");
+                    }
+                    TlsAction::Ccc => {
+                        write_line(stream.get_mut(), "200 CCC command successful");
+                        stream = BufReader::new(stream.into_inner().clear(true));
+                    }
+                    TlsAction::CccWithoutCloseNotify => {
+                        write_line(stream.get_mut(), "200 CCC command successful");
+                        stream = BufReader::new(stream.into_inner().clear(false));
+                    }
+                }
+                received.push(command);
+            }
+            received
+        });
+        Self { addr, handle }
+    }
+
+    /// Address the mock server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the script to complete and return the commands received by the server
+    pub fn join(self) -> Vec<String> {
+        self.handle.join().unwrap()
+    }
+}
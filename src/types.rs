@@ -5,6 +5,9 @@
 use super::Status;
 use std::convert::From;
 use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use thiserror::Error;
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -41,6 +44,31 @@ pub enum FtpError {
     /// The address provided was invalid
     #[error("Invalid address: {0}")]
     InvalidAddress(std::net::AddrParseError),
+
+    /// The operation didn't complete before the configured timeout (connect, control reply or data idle)
+    #[error("Operation timed out")]
+    Timeout,
+
+    /// The proxy refused the request or replied with an invalid message.
+    /// For HTTP proxies, contains the status line of the proxy response.
+    #[error("Proxy error: {0}")]
+    ProxyError(String),
+
+    /// The host name resolved to several addresses, and the connection failed for all of them.
+    /// Contains the error of each address, in the order the attempts failed.
+    #[error("Could not connect to any address: {}", fmt_connect_errors(.0))]
+    ConnectFailed(Vec<(SocketAddr, FtpError)>),
+
+    /// The data connection address returned by the server was refused by the `PassiveTargetPolicy`
+    /// (e.g. an FTP bounce attempt towards another host)
+    #[error("Refused data connection address: {0}")]
+    UnsafeDataAddress(SocketAddr),
+
+    /// The certificate chain presented by the server was rejected by the `CertificateVerifier`
+    /// (e.g. it doesn't match the pinned certificate)
+    #[cfg(feature = "_secure")]
+    #[error("Server certificate rejected: {0}")]
+    CertificateRejected(String),
     
     /// 500 Syntax error, command unrecognized (this may include errors such as command line too long).
     /// 502 Command not implemented.
@@ -67,17 +95,55 @@ impl FtpError {
                 }
             },
 
+            // The same client certificate would be rejected again
             #[cfg(feature = "_secure")]
-            FtpError::SecureError(_) => { true },
+            FtpError::SecureError(msg) => { !msg.starts_with(CLIENT_CERTIFICATE_REJECTED) },
+
+            FtpError::Timeout => { true },
+
+            FtpError::ConnectFailed(errors) => errors.iter().any(|(_, err)| err.is_recoverable()),
 
             _ => { false },
         }
     }
+
+    /// Convert the io errors raised when a socket timeout set by the library expires into `FtpError::Timeout`.
+    /// Socket timeouts are reported as `WouldBlock` on unix systems and as `TimedOut` on windows.
+    pub(crate) fn socket_timeout(self) -> Self {
+        match self {
+            FtpError::ConnectionError(ioe)
+                if matches!(ioe.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) =>
+            {
+                FtpError::Timeout
+            }
+            err => err,
+        }
+    }
+}
+
+/// Beginning of the message of the `SecureError` returned when the server rejects the client certificate
+#[cfg(feature = "_secure")]
+const CLIENT_CERTIFICATE_REJECTED: &str = "the server rejected the client certificate";
+
+fn fmt_connect_errors(errors: &[(SocketAddr, FtpError)]) -> String {
+    errors
+        .iter()
+        .map(|(addr, err)| format!("{}: {}", addr, err))
+        .collect::<Vec<String>>()
+        .join("; ")
 }
 
 impl From<std::io::Error> for FtpError {
     fn from(e: std::io::Error) -> Self {
-        Self::ConnectionError(e)
+        match e.kind() {
+            // TLS errors raised by rustls while reading or writing (e.g. the server rejected the client certificate)
+            #[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+            _ if e.get_ref().map(|inner| inner.is::<rustls::Error>()).unwrap_or(false) => {
+                let inner = e.into_inner().expect("error has an inner error");
+                Self::from(*inner.downcast::<rustls::Error>().expect("inner error is a rustls error"))
+            },
+            _ => Self::ConnectionError(e),
+        }
     }
 }
 
@@ -97,6 +163,38 @@ where
     }
 }
 
+/// Reason why a `CertificateVerifier` rejected the server certificate during a rustls handshake
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub(crate) struct CertificateRejection(pub String);
+
+#[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+impl From<rustls::Error> for FtpError {
+    fn from(e: rustls::Error) -> Self {
+        use rustls::AlertDescription;
+
+        match e {
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Other(ref err))
+                if err.downcast_ref::<CertificateRejection>().is_some() =>
+            {
+                let reason = err.downcast_ref::<CertificateRejection>().expect("error is a certificate rejection");
+                FtpError::CertificateRejected(reason.0.clone())
+            },
+            rustls::Error::AlertReceived(
+                alert @ (AlertDescription::BadCertificate
+                | AlertDescription::CertificateRequired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::UnknownCA
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateRevoked),
+            ) => FtpError::SecureError(format!("{} ({:?})", CLIENT_CERTIFICATE_REJECTED, alert)),
+            e => FtpError::SecureError(format!("{}", e)),
+        }
+    }
+}
+
 #[cfg(feature = "async-secure")]
 impl From<async_native_tls::Error> for FtpError
 {
@@ -346,8 +444,257 @@ impl ToString for FileType {
 /// Connection mode for data channel
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
+    /// Passive mode (`PASV`): the client connects to the address returned by the server
     Passive,
+    /// Active mode (`PORT`): the server connects to a listener opened by the client
     Active,
+    /// Extended passive mode (`EPSV`, RFC 2428): the client connects to the control connection peer,
+    /// on the port returned by the server. Falls back to `PASV` if the server doesn't support it.
+    ExtendedPassive,
+    /// Extended active mode (`EPRT`, RFC 2428): same as active mode, but the listener address
+    /// is sent in a format which supports both IPv4 and IPv6
+    ExtendedActive,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Configuration of the listener opened for data connections in active mode (`PORT`/`EPRT`)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ActiveModeConfig {
+    /// Local address the listener is bound to. If not set, the listener is bound to all the interfaces
+    pub bind_address: Option<IpAddr>,
+    /// Range of local ports the listener can be bound to. Busy ports are skipped.
+    /// If not set, the port is chosen by the operating system
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// Address advertised to the server with `PORT`/`EPRT`; useful when the client is behind NAT.
+    /// If not set, the bind address (or the local address of the control connection) is advertised
+    pub external_ip: Option<IpAddr>,
+    /// Maximum time to wait for the server to open the data connection.
+    /// If not set, the client waits indefinitely
+    pub accept_timeout: Option<Duration>,
+    /// Accept data connections from any host. By default, connections which don't come from the address
+    /// of the control connection peer are dropped, to prevent data channel hijacking
+    pub allow_any_peer: bool,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Defines which address is used to open the data connection in passive mode (`PASV`).
+/// Servers behind NAT often return their private address in the `PASV` reply; in this case
+/// the address of the control connection peer should be used instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassiveAddressPolicy {
+    /// Use the address returned by the server
+    UseReplyAddress,
+    /// Always use the address of the control connection peer, ignoring the one returned by the server
+    UsePeerAddress,
+    /// Use the address of the control connection peer if the address returned by the server is private,
+    /// loopback, link-local or unspecified
+    UsePeerAddressIfPrivate,
+}
+
+impl Default for PassiveAddressPolicy {
+    fn default() -> Self {
+        Self::UseReplyAddress
+    }
+}
+
+impl PassiveAddressPolicy {
+    /// Returns the address to connect to, given the address returned by the server and the control connection peer
+    pub fn resolve(&self, reply_addr: SocketAddr, peer_ip: IpAddr) -> SocketAddr {
+        let use_peer = match self {
+            Self::UseReplyAddress => false,
+            Self::UsePeerAddress => true,
+            Self::UsePeerAddressIfPrivate => match reply_addr.ip() {
+                IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+                IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+            },
+        };
+
+        if use_peer {
+            SocketAddr::new(peer_ip, reply_addr.port())
+        } else {
+            reply_addr
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Defines which data connection addresses returned by the server in passive mode are accepted.
+/// A malicious server could otherwise make the client connect to arbitrary hosts and ports (FTP bounce).
+/// The policy is checked after the `PassiveAddressPolicy` has been applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassiveTargetPolicy {
+    /// Refuse addresses whose IP differs from the control connection peer. Enabled by default
+    pub require_peer_ip: bool,
+    /// IPs accepted even if they differ from the control connection peer (e.g. for multi-homed servers)
+    pub allowed_ips: Vec<IpAddr>,
+    /// Refuse ports lower than 1024. Disabled by default
+    pub refuse_privileged_ports: bool,
+}
+
+impl Default for PassiveTargetPolicy {
+    fn default() -> Self {
+        Self {
+            require_peer_ip: true,
+            allowed_ips: Vec::new(),
+            refuse_privileged_ports: false,
+        }
+    }
+}
+
+impl PassiveTargetPolicy {
+    /// Policy which accepts any address; use it only with trusted servers
+    pub fn allow_all() -> Self {
+        Self {
+            require_peer_ip: false,
+            allowed_ips: Vec::new(),
+            refuse_privileged_ports: false,
+        }
+    }
+
+    /// Check the address to connect to, given the control connection peer (if known)
+    pub fn check(&self, addr: SocketAddr, peer_ip: Option<IpAddr>) -> FtpResult<()> {
+        if self.refuse_privileged_ports && addr.port() < 1024 {
+            return Err(FtpError::UnsafeDataAddress(addr));
+        }
+        match peer_ip {
+            Some(peer_ip) if self.require_peer_ip && addr.ip() != peer_ip && !self.allowed_ips.contains(&addr.ip()) => {
+                Err(FtpError::UnsafeDataAddress(addr))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Keep-alive configuration, used to prevent firewalls and servers from dropping idle connections.
+/// Keep-alive is disabled by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeepAliveConfig {
+    /// Interval after which a `NOOP` is sent on the control connection, both while idle and during transfers.
+    /// If not set, no `NOOP` is sent
+    pub noop_interval: Option<Duration>,
+    /// Idle time after which TCP keepalive probes are sent on the control and data connections.
+    /// If not set, TCP keepalive is left unchanged
+    pub tcp_keepalive: Option<Duration>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Defines how a lost session is re-established: how many times the connection is retried
+/// and how long to wait before each attempt (exponential backoff)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Maximum number of reconnection attempts for a single operation
+    pub max_retries: usize,
+    /// Delay before the first reconnection attempt
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_delay: Duration,
+    /// Factor the delay is multiplied by after every attempt
+    pub multiplier: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay to wait before the reconnection attempt number `attempt` (starting from 0)
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let factor = u32::try_from(attempt)
+            .ok()
+            .and_then(|attempt| self.multiplier.checked_pow(attempt));
+        match factor.and_then(|factor| self.initial_delay.checked_mul(factor)) {
+            Some(delay) => delay.min(self.max_delay),
+            None => self.max_delay,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Proxy used to reach the FTP server. The control connection and all the data connections are tunneled through it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// SOCKS5 proxy (RFC 1928) at `addr` (`host:port`; port 1080 is used if missing).
+    /// If `credentials` are provided, username/password authentication (RFC 1929) is offered to the proxy.
+    /// Active mode data connections use the SOCKS `BIND` command.
+    Socks5 {
+        addr: String,
+        credentials: Option<ProxyCredentials>,
+    },
+    /// HTTP proxy at `addr` (`host:port`; port 8080 is used if missing), which tunnels connections with the `CONNECT` method.
+    /// If `credentials` are provided, they're sent with Basic authentication.
+    /// Active mode is not supported, since the proxy can't accept connections for the client.
+    Http {
+        addr: String,
+        credentials: Option<ProxyCredentials>,
+    },
+}
+
+/// Login style of an FTP proxy (gateway), which connects to the real host on behalf of the client.
+/// `fwuser` and `fwpass` are the proxy credentials, `user` and `pass` the credentials on the real `host`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FtpProxyKind {
+    /// `USER user@host`, `PASS pass`
+    UserAtHost,
+    /// `USER fwuser`, `PASS fwpass`, then `USER user@host`, `PASS pass`
+    UserAtHostAfterLogin,
+    /// `USER fwuser`, `PASS fwpass` (if proxy credentials are provided), then `SITE host`, `USER user`, `PASS pass`
+    Site,
+    /// `USER fwuser`, `PASS fwpass` (if proxy credentials are provided), then `OPEN host`, `USER user`, `PASS pass`
+    Open,
+    /// `USER fwuser@host`, `PASS fwpass`, then `USER user`, `PASS pass`
+    ProxyUserAtHost,
+    /// `USER user@fwuser@host`, `PASS pass@fwpass`
+    UserAtProxyUserAtHost,
+    /// `USER user@host fwuser`, `PASS pass`, then `ACCT fwpass` if the proxy asks for an account
+    UserAtHostProxyUserAcct,
+}
+
+/// FTP proxy (gateway) configuration. The FTP stream must be connected to the proxy;
+/// `login` then performs the command sequence of `kind` to reach `host` (`host` or `host:port`, as expected by the proxy).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FtpProxy {
+    pub kind: FtpProxyKind,
+    pub host: String,
+    pub credentials: Option<ProxyCredentials>,
+}
+
+/// Credentials used to authenticate to a proxy
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyCredentials {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -384,6 +731,96 @@ mod test {
             FtpError::BadResponse.to_string().as_str(),
             "Response contains an invalid syntax"
         );
+        assert_eq!(FtpError::Timeout.to_string().as_str(), "Operation timed out");
+        assert_eq!(
+            FtpError::ProxyError("connection refused".to_string()).to_string().as_str(),
+            "Proxy error: connection refused"
+        );
+        assert_eq!(
+            FtpError::ConnectFailed(vec![
+                ("[::1]:21".parse().unwrap(), FtpError::Timeout),
+                ("127.0.0.1:21".parse().unwrap(), FtpError::BadResponse),
+            ])
+            .to_string()
+            .as_str(),
+            "Could not connect to any address: [::1]:21: Operation timed out; 127.0.0.1:21: Response contains an invalid syntax"
+        );
+        assert_eq!(
+            FtpError::UnsafeDataAddress("10.0.0.2:22".parse().unwrap()).to_string().as_str(),
+            "Refused data connection address: 10.0.0.2:22"
+        );
+    }
+
+    #[test]
+    fn should_convert_io_timeout_to_timeout_error() {
+        assert!(matches!(
+            FtpError::from(std::io::Error::from(std::io::ErrorKind::TimedOut)).socket_timeout(),
+            FtpError::Timeout
+        ));
+        assert!(matches!(
+            FtpError::from(std::io::Error::from(std::io::ErrorKind::WouldBlock)).socket_timeout(),
+            FtpError::Timeout
+        ));
+        assert!(matches!(
+            FtpError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).socket_timeout(),
+            FtpError::ConnectionError(_)
+        ));
+        // Errors of other readers and writers (e.g. a non-blocking reader given to `put_file`) are not timeouts
+        assert!(matches!(
+            FtpError::from(std::io::Error::from(std::io::ErrorKind::WouldBlock)),
+            FtpError::ConnectionError(_)
+        ));
+        assert!(FtpError::Timeout.is_recoverable());
+    }
+
+    #[test]
+    #[cfg(any(feature = "sync-rustls", feature = "async-rustls"))]
+    fn should_not_recover_from_rejected_client_certificate() {
+        let err = FtpError::from(rustls::Error::AlertReceived(rustls::AlertDescription::CertificateRequired));
+        assert_eq!(
+            err.to_string().as_str(),
+            "Secure error: the server rejected the client certificate (CertificateRequired)"
+        );
+        assert!(!err.is_recoverable());
+        assert!(FtpError::from(rustls::Error::DecryptError).is_recoverable());
+    }
+
+    #[test]
+    fn should_check_passive_target() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let policy = PassiveTargetPolicy::default();
+        assert!(policy.check("10.0.0.1:20000".parse().unwrap(), Some(peer)).is_ok());
+        assert!(policy.check("10.0.0.1:22".parse().unwrap(), Some(peer)).is_ok());
+        assert!(matches!(
+            policy.check("10.0.0.2:20000".parse().unwrap(), Some(peer)),
+            Err(FtpError::UnsafeDataAddress(_))
+        ));
+        // Peer unknown (e.g. resolved by a proxy)
+        assert!(policy.check("10.0.0.2:20000".parse().unwrap(), None).is_ok());
+        let policy = PassiveTargetPolicy {
+            allowed_ips: vec!["10.0.0.2".parse().unwrap()],
+            refuse_privileged_ports: true,
+            ..PassiveTargetPolicy::default()
+        };
+        assert!(policy.check("10.0.0.2:20000".parse().unwrap(), Some(peer)).is_ok());
+        assert!(policy.check("10.0.0.3:20000".parse().unwrap(), Some(peer)).is_err());
+        assert!(policy.check("10.0.0.1:22".parse().unwrap(), Some(peer)).is_err());
+        assert!(PassiveTargetPolicy::allow_all().check("10.0.0.3:22".parse().unwrap(), Some(peer)).is_ok());
+    }
+
+    #[test]
+    fn should_compute_reconnect_delay() {
+        let policy = ReconnectPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2,
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+        assert_eq!(policy.delay(4), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
     }
 
     #[test]
@@ -405,6 +842,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn should_resolve_passive_address() {
+        let peer_ip: IpAddr = "203.0.113.10".parse().unwrap();
+        let private: SocketAddr = "192.168.1.1:30000".parse().unwrap();
+        let unspecified: SocketAddr = "0.0.0.0:30000".parse().unwrap();
+        let public: SocketAddr = "198.51.100.1:30000".parse().unwrap();
+        let peer: SocketAddr = "203.0.113.10:30000".parse().unwrap();
+        // use reply
+        assert_eq!(PassiveAddressPolicy::default(), PassiveAddressPolicy::UseReplyAddress);
+        assert_eq!(PassiveAddressPolicy::UseReplyAddress.resolve(private, peer_ip), private);
+        assert_eq!(PassiveAddressPolicy::UseReplyAddress.resolve(public, peer_ip), public);
+        // use peer
+        assert_eq!(PassiveAddressPolicy::UsePeerAddress.resolve(private, peer_ip), peer);
+        assert_eq!(PassiveAddressPolicy::UsePeerAddress.resolve(public, peer_ip), peer);
+        // use peer if private
+        assert_eq!(PassiveAddressPolicy::UsePeerAddressIfPrivate.resolve(private, peer_ip), peer);
+        assert_eq!(PassiveAddressPolicy::UsePeerAddressIfPrivate.resolve(unspecified, peer_ip), peer);
+        assert_eq!(PassiveAddressPolicy::UsePeerAddressIfPrivate.resolve(public, peer_ip), public);
+    }
+
     #[test]
     fn fmt_format_control() {
         assert_eq!(FormatControl::Asa.to_string().as_str(), "C");
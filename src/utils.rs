@@ -9,6 +9,10 @@ lazy_static! {
     // The regex looks for the pattern (h1,h2,h3,h4,p1,p2).
     pub static ref PORT_RE: Regex = Regex::new(r"\((\d+),(\d+),(\d+),(\d+),(\d+),(\d+)\)").unwrap();
 
+    // This regex extracts the port from EPSV command response.
+    // The regex looks for the pattern (<d><d><d>port<d>), where <d> is the delimiter (usually '|').
+    pub static ref EPSV_PORT_RE: Regex = Regex::new(r"\(([!-~])([!-~])([!-~])(\d+)([!-~])\)").unwrap();
+
     // This regex extracts modification time from MDTM command response.
     pub static ref MDTM_RE: Regex = Regex::new(r"\b(\d{4})(\d{2})(\d{2})(\d{2})(\d{2})(\d{2})\b").unwrap();
 